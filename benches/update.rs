@@ -0,0 +1,145 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+#![feature(test)]
+extern crate heroesoftheswarm;
+extern crate test;
+
+use heroesoftheswarm::entity::Swarm;
+use heroesoftheswarm::spatial::SpatialGrid;
+use heroesoftheswarm::world::World;
+use test::Bencher;
+
+/// Number of updates timed for each copy of a world
+/// Copying a busy world is slow, so it is shared between several updates
+const UPDATES: usize = 10;
+
+/// Radius of each swarm member's hitbox, as in World::update
+const MEMBER_RADIUS: f32 = 10.0;
+
+/// Builds a world where every swarm hunts and fires as often as it can
+/// The world is run for a while first so it is full of bullets
+/// Each iteration updates a fresh copy, since swarms soon destroy each other
+fn busy_world(num_swarms: usize) -> World {
+    // Keep roughly the same number of swarms per area as a full 1600x900 server
+    let side = (num_swarms as f32).sqrt() * 300.0;
+    let mut world = World::new(side, side).with_seed(1);
+    for id in 0..num_swarms {
+        world.add_player(id);
+        world.update_program(
+            id,
+            "AIM NEAREST\nMOVE\nFIRE\nTURN 15\nMOVE".parse().unwrap(),
+        );
+    }
+    for _ in 0..120 {
+        world.update();
+    }
+    world
+}
+
+/// Times several updates of a fresh copy of a world
+fn bench_update(bencher: &mut Bencher, num_swarms: usize) {
+    let world = busy_world(num_swarms);
+    bencher.iter(|| {
+        let mut world = world.clone();
+        for _ in 0..UPDATES {
+            world.update();
+        }
+        world
+    });
+}
+
+/// Whether a point is in the area a swarm can be hit in
+fn in_reach(swarm: &Swarm, x: f32, y: f32) -> bool {
+    let epsilon: f32 = swarm.extent() + MEMBER_RADIUS;
+    (x - swarm.x).abs() <= epsilon && (y - swarm.y).abs() <= epsilon
+}
+
+/// Counts the swarms each bullet could hit by checking every swarm
+/// This is how World::update found collisions before it used a grid
+fn collide_all_pairs(world: &World) -> usize {
+    world
+        .bullets
+        .iter()
+        .map(|bullet| {
+            world
+                .swarms
+                .iter()
+                .filter(|&(&id, swarm)| id != bullet.owner && in_reach(swarm, bullet.x, bullet.y))
+                .count()
+        })
+        .sum()
+}
+
+/// Counts the swarms each bullet could hit by checking the swarms near it in a grid
+fn collide_grid(world: &World) -> usize {
+    let mut grid = SpatialGrid::new(world.width, world.height, SpatialGrid::CELL_SIZE);
+    for (id, swarm) in world.swarms.iter() {
+        let epsilon: f32 = swarm.extent() + MEMBER_RADIUS;
+        grid.insert(
+            *id,
+            swarm.x - epsilon,
+            swarm.y - epsilon,
+            swarm.x + epsilon,
+            swarm.y + epsilon,
+        );
+    }
+    world
+        .bullets
+        .iter()
+        .map(|bullet| {
+            grid.query(bullet.x, bullet.y)
+                .iter()
+                .filter(|&&id| id != bullet.owner && in_reach(&world.swarms[&id], bullet.x, bullet.y))
+                .count()
+        })
+        .sum()
+}
+
+#[bench]
+fn update_20_swarms(bencher: &mut Bencher) {
+    bench_update(bencher, 20);
+}
+
+#[bench]
+fn update_100_swarms(bencher: &mut Bencher) {
+    bench_update(bencher, 100);
+}
+
+#[bench]
+fn update_200_swarms(bencher: &mut Bencher) {
+    bench_update(bencher, 200);
+}
+
+#[bench]
+fn clone_200_swarms(bencher: &mut Bencher) {
+    // The cost of copying the world, which is part of each update benchmark
+    let world = busy_world(200);
+    bencher.iter(|| world.clone());
+}
+
+#[bench]
+fn collide_all_pairs_200_swarms(bencher: &mut Bencher) {
+    let world = busy_world(200);
+    bencher.iter(|| collide_all_pairs(&world));
+}
+
+#[bench]
+fn collide_grid_200_swarms(bencher: &mut Bencher) {
+    let world = busy_world(200);
+    // Both checks must find the same collisions to be compared
+    assert_eq!(collide_grid(&world), collide_all_pairs(&world));
+    bencher.iter(|| collide_grid(&world));
+}
@@ -15,14 +15,228 @@
 // along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
 extern crate env_logger;
 extern crate heroesoftheswarm;
+extern crate serde_json;
 
-use heroesoftheswarm::server;
+use heroesoftheswarm::config::{validate_size, Rules, ServerConfig};
+use heroesoftheswarm::replay::Replay;
+use heroesoftheswarm::server::GameServer;
+use heroesoftheswarm::simulation::{load_directory, load_program, Simulation};
+use heroesoftheswarm::tournament::Tournament;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+/// How to run the server and the headless modes
+const USAGE: &str = "Usage:
+    heroesoftheswarm [--config FILE] [OPTION VALUE]...
+    heroesoftheswarm simulate [--width W] [--height H] [--ticks N] [--seed S] [RULE VALUE]... PROGRAM...
+    heroesoftheswarm tournament [--width W] [--height H] [--ticks N] [--seeds N] [--group N] [--json] [RULE VALUE]... DIRECTORY
+    heroesoftheswarm replay [--tick N] REPLAY
+
+Server options, which override the config file:
+    --host, --port, --tick-rate, --broadcast-interval, --keyframe-interval, --width, --height,
+    --max-players, --snapshot (or --no-snapshot), --replay, and any rule
+Rules, for the server, simulations and tournaments:
+    --max-program-length, --initial-swarm-size, --max-swarm-size, --growth-threshold,
+    --bullet-lifetime, --fire-cooldown, --formation-cooldown, --cycle-budget
+The world is only saved with --snapshot or a snapshot_path in the config file";
 
 fn main() {
     // Initialize the logger
     env_logger::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|arg| arg.as_str()) {
+        Some("simulate") => simulate(&args[1..]),
+        Some("tournament") => tournament(&args[1..]),
+        Some("replay") => replay(&args[1..]),
+        _ => serve(&args),
+    }
+}
+
+/// Runs the game server
+/// Settings come from the defaults, then the config file, then the options given
+fn serve(args: &[String]) {
+    // The config file is read first so the other options can override it
+    let mut config = match args.iter().position(|arg| arg == "--config") {
+        Some(index) => {
+            let path: String = parse_value("--config", args.get(index + 1));
+            ServerConfig::load(Path::new(&path)).unwrap_or_else(|error| fail(&error.description))
+        }
+        None => ServerConfig::default(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                args.next();
+            }
+            "--host" => config.hostname = parse_value(arg, args.next()),
+            "--port" => config.port = parse_value(arg, args.next()),
+            "--tick-rate" => config.update_freq = parse_value(arg, args.next()),
+            "--broadcast-interval" => config.broadcast_interval = parse_value(arg, args.next()),
+            "--keyframe-interval" => config.keyframe_interval = parse_value(arg, args.next()),
+            "--width" => config.width = parse_value(arg, args.next()),
+            "--height" => config.height = parse_value(arg, args.next()),
+            "--max-players" => config.max_players = parse_value(arg, args.next()),
+            "--snapshot" => {
+                let path: String = parse_value(arg, args.next());
+                config.snapshot_path = Some(PathBuf::from(path));
+            }
+            "--no-snapshot" => config.snapshot_path = None,
+            "--replay" => {
+                let path: String = parse_value(arg, args.next());
+                config.replay_path = Some(PathBuf::from(path));
+            }
+            _ => set_rule(&mut config.rules, arg, args.next()),
+        }
+    }
+    if let Err(error) = config.validate() {
+        fail(&error.description);
+    }
     // Initialize a server
-    //let game_server = server::GameServer::new("127.0.0.1", 5977, 1000.0, 1000.0, 1);
-    //TODO: change this once server is an object
-    server::run();
+    let game_server = GameServer::new(config);
+    if let Err(error) = game_server.start().and_then(|server| server.wait()) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+/// Plays a match between program files without a server
+/// Prints the results as JSON
+fn simulate(args: &[String]) {
+    let mut simulation = Simulation::new(1600.0, 900.0, 3600, 0);
+    let mut paths: Vec<&str> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => simulation.width = parse_value(arg, args.next()),
+            "--height" => simulation.height = parse_value(arg, args.next()),
+            "--ticks" => simulation.ticks = parse_value(arg, args.next()),
+            "--seed" => simulation.seed = parse_value(arg, args.next()),
+            _ if arg.starts_with("--") => set_rule(&mut simulation.rules, arg, args.next()),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        fail("No programs given");
+    }
+    let valid = validate_size(simulation.width, simulation.height)
+        .and_then(|_| simulation.rules.validate());
+    if let Err(error) = valid {
+        fail(&error.description);
+    }
+    // Load every program before playing
+    let max_length = simulation.rules.max_program_length;
+    let programs: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            load_program(Path::new(path), max_length).unwrap_or_else(|error| fail(&error.description))
+        })
+        .collect();
+    let result = simulation.run(&programs);
+    match serde_json::to_string_pretty(&result) {
+        Ok(json) => println!("{}", json),
+        Err(error) => fail(&format!("Failed to serialize results: {}", error)),
+    }
+}
+
+/// Plays a round-robin tournament between the programs in a directory
+/// Prints the standings as a table, or as JSON with --json
+fn tournament(args: &[String]) {
+    let mut tournament = Tournament::new(1600.0, 900.0, 3600);
+    let mut json = false;
+    let mut directory: Option<&str> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => tournament.width = parse_value(arg, args.next()),
+            "--height" => tournament.height = parse_value(arg, args.next()),
+            "--ticks" => tournament.ticks = parse_value(arg, args.next()),
+            "--seeds" => match parse_value(arg, args.next()) {
+                0 => fail("--seeds must be at least 1"),
+                seeds => tournament.seeds = (0..seeds).collect(),
+            },
+            "--group" => tournament.group_size = parse_value(arg, args.next()),
+            "--json" => json = true,
+            _ if arg.starts_with("--") => set_rule(&mut tournament.rules, arg, args.next()),
+            _ if directory.is_none() => directory = Some(arg),
+            _ => fail("Only one directory can be given"),
+        }
+    }
+    let directory = directory.unwrap_or_else(|| fail("No directory given"));
+    let valid = validate_size(tournament.width, tournament.height)
+        .and_then(|_| tournament.rules.validate());
+    if let Err(error) = valid {
+        fail(&error.description);
+    }
+    let max_length = tournament.rules.max_program_length;
+    let programs = load_directory(Path::new(directory), max_length)
+        .unwrap_or_else(|error| fail(&error.description));
+    if programs.len() < 2 {
+        fail(&format!("{} has fewer than 2 .swarm programs", directory));
+    }
+    let result = tournament.run(&programs);
+    if json {
+        match serde_json::to_string_pretty(&result) {
+            Ok(json) => println!("{}", json),
+            Err(error) => fail(&format!("Failed to serialize results: {}", error)),
+        }
+    } else {
+        println!("{}", result);
+    }
+}
+
+/// Plays back a replay file and prints the world's state at a tick as JSON
+/// Without --tick, plays until the last recorded event
+fn replay(args: &[String]) {
+    let mut tick: Option<u64> = None;
+    let mut path: Option<&str> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tick" => tick = Some(parse_value(arg, args.next())),
+            _ if arg.starts_with("--") => fail(&format!("Unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => fail("Only one replay can be given"),
+        }
+    }
+    let path = path.unwrap_or_else(|| fail("No replay given"));
+    let replay = Replay::load(Path::new(path)).unwrap_or_else(|error| fail(&error.description));
+    let tick = tick.unwrap_or_else(|| replay.events.last().map_or(0, |&(tick, _)| tick));
+    let world = replay.play(tick).unwrap_or_else(|error| fail(&error.description));
+    match serde_json::to_string_pretty(&world.get_state()) {
+        Ok(json) => println!("{}", json),
+        Err(error) => fail(&format!("Failed to serialize the world: {}", error)),
+    }
+}
+
+/// Sets a rule from its option and the value given after it
+/// Fails on options that aren't rules
+fn set_rule(rules: &mut Rules, option: &str, value: Option<&String>) {
+    match option {
+        "--max-program-length" => rules.max_program_length = parse_value(option, value),
+        "--initial-swarm-size" => rules.initial_swarm_size = parse_value(option, value),
+        "--max-swarm-size" => rules.max_swarm_size = parse_value(option, value),
+        "--growth-threshold" => rules.growth_threshold = parse_value(option, value),
+        "--bullet-lifetime" => rules.bullet_lifetime = parse_value(option, value),
+        "--fire-cooldown" => rules.fire_cooldown = parse_value(option, value),
+        "--formation-cooldown" => rules.formation_cooldown = parse_value(option, value),
+        "--cycle-budget" => rules.cycle_budget = parse_value(option, value),
+        _ => fail(&format!("Unknown option {}", option)),
+    }
+}
+
+/// Parses the value given after an option
+fn parse_value<T: FromStr>(option: &str, value: Option<&String>) -> T {
+    match value.map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        _ => fail(&format!("{} needs a valid value", option)),
+    }
+}
+
+/// Prints an error with the usage and exits
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(1)
 }
@@ -0,0 +1,308 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+use futures::{Async, Poll, Stream};
+use futures::task::{self, Task};
+use delta::DeltaEncoder;
+use rpc::{Response, Viewport};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use world::WorldState;
+
+/// The newest frame waiting to be sent to a client
+#[derive(Default)]
+struct Slot {
+    /// The frame waiting to be sent, if any
+    frame: Option<String>,
+    /// Whether no more frames will be sent
+    closed: bool,
+    /// The task sending frames to the client, woken when a frame arrives
+    task: Option<Task>,
+}
+
+/// Functions for a slot
+impl Slot {
+    /// Wakes the task sending frames, if it is waiting
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+/// Creates a queue holding the newest frame for one client
+pub fn channel() -> (FrameSender, Frames) {
+    let slot = Arc::new(Mutex::new(Slot::default()));
+    (FrameSender { slot: slot.clone() }, Frames { slot: slot })
+}
+
+/// Puts frames in a client's queue
+/// The client's frames end once this is dropped
+pub struct FrameSender {
+    /// The queue shared with the client's connection
+    slot: Arc<Mutex<Slot>>,
+}
+
+/// Functions for a frame sender
+impl FrameSender {
+    /// Queues a frame, replacing the last one if the client hasn't been sent it yet
+    /// Returns whether a frame was dropped
+    pub fn send(&self, frame: String) -> bool {
+        match self.slot.lock() {
+            Ok(mut slot) => {
+                let stale = slot.frame.is_some();
+                slot.frame = Some(frame);
+                slot.notify();
+                stale
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Closes the client's frames
+impl Drop for FrameSender {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.slot.lock() {
+            slot.closed = true;
+            slot.notify();
+        }
+    }
+}
+
+/// The frames for one client, as a stream for its connection to send
+/// Only the newest frame is kept, so slow clients skip frames instead of falling behind
+pub struct Frames {
+    /// The queue shared with the frame sender
+    slot: Arc<Mutex<Slot>>,
+}
+
+/// Allows frames to be sent as they arrive
+impl Stream for Frames {
+    type Item = String;
+    type Error = ();
+    /// Takes the newest frame, or waits for one
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        let mut slot = self.slot.lock().map_err(|_| ())?;
+        match slot.frame.take() {
+            Some(frame) => Ok(Async::Ready(Some(frame))),
+            None if slot.closed => Ok(Async::Ready(None)),
+            None => {
+                slot.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// A connected client
+struct Client {
+    /// The client's queue
+    sender: FrameSender,
+    /// The area of the world the client is showing, once it has said
+    viewport: Option<Viewport>,
+    /// Decides whether the client is sent a whole state or changes
+    encoder: DeltaEncoder,
+}
+
+/// Pushes frames to every connected client
+pub struct Broadcaster {
+    /// Each client, by player ID
+    clients: Mutex<BTreeMap<usize, Client>>,
+    /// Most ticks between whole world states sent to a client
+    keyframe_interval: u64,
+}
+
+/// Functions for a broadcaster
+impl Broadcaster {
+    /// Constructor for a broadcaster with no clients
+    pub fn new(keyframe_interval: u64) -> Self {
+        Broadcaster {
+            clients: Mutex::new(BTreeMap::new()),
+            keyframe_interval: keyframe_interval,
+        }
+    }
+    /// Adds a client, returning the frames to send it
+    pub fn subscribe(&self, id: usize) -> Frames {
+        let (sender, frames) = channel();
+        match self.clients.lock() {
+            Ok(mut clients) => {
+                clients.insert(
+                    id,
+                    Client {
+                        sender: sender,
+                        viewport: None,
+                        encoder: DeltaEncoder::new(self.keyframe_interval),
+                    },
+                );
+            }
+            Err(_) => error!("Failed to lock the clients. Client {} won't get frames", id),
+        }
+        frames
+    }
+    /// Removes a client, ending its frames
+    pub fn unsubscribe(&self, id: usize) {
+        match self.clients.lock() {
+            Ok(mut clients) => {
+                clients.remove(&id);
+            }
+            Err(_) => error!("Failed to lock the clients. Client {} not removed", id),
+        }
+    }
+    /// Sets the area of the world a client is showing
+    pub fn set_viewport(&self, id: usize, viewport: Viewport) {
+        match self.clients.lock() {
+            Ok(mut clients) => if let Some(client) = clients.get_mut(&id) {
+                client.viewport = Some(viewport);
+            },
+            Err(_) => error!("Failed to lock the clients. Viewport of Client {} not set", id),
+        }
+    }
+    /// Records that a client received the world state from a tick
+    pub fn acknowledge(&self, id: usize, tick: u64) {
+        match self.clients.lock() {
+            Ok(mut clients) => if let Some(client) = clients.get_mut(&id) {
+                client.encoder.acknowledge(tick);
+            },
+            Err(_) => error!("Failed to lock the clients. Acknowledgement from Client {} lost", id),
+        }
+    }
+    /// Whether any clients are connected
+    pub fn is_empty(&self) -> bool {
+        self.clients.lock().map(|clients| clients.is_empty()).unwrap_or(true)
+    }
+    /// Queues a world state built for each client from its ID and viewport
+    /// Each client is sent the state or the changes since the last one it acknowledged
+    /// Clients without a viewport must all be given the same state
+    pub fn broadcast_world<F>(&self, mut state_for: F)
+    where
+        F: FnMut(usize, Option<&Viewport>) -> Arc<WorldState>,
+    {
+        match self.clients.lock() {
+            Ok(mut clients) => {
+                // Frames for clients without a viewport, by the tick they are based on
+                // Those clients have never had a viewport, so they were all sent the same states
+                // Clients with the same base get the same frame, which is only serialized once
+                let mut shared_frames: BTreeMap<Option<u64>, String> = BTreeMap::new();
+                for (id, client) in clients.iter_mut() {
+                    let state = state_for(*id, client.viewport.as_ref());
+                    let frame = if client.viewport.is_none() {
+                        let base = client.encoder.base(state.tick);
+                        match shared_frames.get(&base).cloned() {
+                            Some(frame) => {
+                                client.encoder.record(state, base);
+                                Ok(frame)
+                            }
+                            None => Response::new(client.encoder.encode(state))
+                                .serialize()
+                                .map(|frame| {
+                                    shared_frames.insert(base, frame.clone());
+                                    frame
+                                }),
+                        }
+                    } else {
+                        Response::new(client.encoder.encode(state)).serialize()
+                    };
+                    match frame {
+                        Ok(frame) => if client.sender.send(frame) {
+                            debug!("Client {} is behind, dropped a frame", id);
+                        },
+                        Err(error) => error!("Failed to serialize the world state: {}", error),
+                    }
+                }
+            }
+            Err(_) => error!("Failed to lock the clients. World state not sent"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, Value};
+    use world::World;
+
+    #[test]
+    fn test_frames() {
+        let (sender, frames) = channel();
+        assert!(!sender.send("1".into()));
+        assert!(sender.send("2".into()));
+        assert!(sender.send("3".into()));
+        drop(sender);
+        // Frames the client wasn't sent in time are replaced by newer ones
+        let sent: Vec<String> = frames.wait().map(|frame| frame.unwrap()).collect();
+        assert_eq!(sent, vec!["3".to_string()]);
+    }
+
+    #[test]
+    fn test_broadcast_world() {
+        let broadcaster = Broadcaster::new(60);
+        // Clients with a viewport are sent the world near it
+        let mut world = World::new(1000.0, 1000.0).with_seed(2);
+        world.add_player(1);
+        world.add_player(2);
+        world.update();
+        let whole = broadcaster.subscribe(1);
+        let culled = broadcaster.subscribe(2);
+        broadcaster.set_viewport(
+            2,
+            Viewport {
+                min_x: -500.0,
+                min_y: -500.0,
+                max_x: -400.0,
+                max_y: -400.0,
+            },
+        );
+        broadcaster.broadcast_world(|id, viewport| match viewport {
+            Some(viewport) => Arc::new(world.get_visible_state(id, viewport)),
+            None => Arc::new(world.get_state()),
+        });
+        broadcaster.unsubscribe(1);
+        broadcaster.unsubscribe(2);
+        assert!(broadcaster.is_empty());
+        let whole: Value = serde_json::from_str(&whole.wait().next().unwrap().unwrap()).unwrap();
+        let culled: Value = serde_json::from_str(&culled.wait().next().unwrap().unwrap()).unwrap();
+        // Nothing has been acknowledged yet, so both get keyframes
+        assert_eq!(whole["mt"], "w");
+        assert_eq!(culled["mt"], "w");
+        assert_eq!(whole["message"]["world"]["swarms"].as_object().unwrap().len(), 2);
+        assert_eq!(culled["message"]["world"]["swarms"].as_object().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_shared_frames() {
+        let broadcaster = Broadcaster::new(60);
+        let mut world = World::new(1000.0, 1000.0).with_seed(2);
+        world.add_player(1);
+        world.update();
+        let frames: Vec<Frames> = (1..4).map(|id| broadcaster.subscribe(id)).collect();
+        broadcaster.broadcast_world(|_, _| Arc::new(world.get_state()));
+        // Only the first client acknowledges the state, so it is the only one sent changes
+        broadcaster.acknowledge(1, world.tick);
+        world.update();
+        broadcaster.broadcast_world(|_, _| Arc::new(world.get_state()));
+        for id in 1..4 {
+            broadcaster.unsubscribe(id);
+        }
+        let sent: Vec<Value> = frames
+            .into_iter()
+            .map(|frames| serde_json::from_str(&frames.wait().next().unwrap().unwrap()).unwrap())
+            .collect();
+        assert_eq!(sent[0]["mt"], "d");
+        assert_eq!(sent[0]["message"]["delta"]["base"], world.tick - 1);
+        assert_eq!(sent[1]["mt"], "w");
+        assert_eq!(sent[1], sent[2]);
+    }
+}
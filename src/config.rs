@@ -0,0 +1,222 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+extern crate serde_json;
+
+use entity::{Bullet, Swarm, INITIAL_SWARM_SIZE, MAX_SWARM_SIZE};
+use error::GenericError;
+use swarm_language::MAX_NUM_COMMANDS;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// The balance of the game, which can be tuned without rebuilding
+/// Settings missing from a config file keep their defaults
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+    /// Most instructions a compiled program can have
+    pub max_program_length: usize,
+    /// Number of members new swarms start with
+    pub initial_swarm_size: usize,
+    /// Most members a swarm can grow to
+    pub max_swarm_size: usize,
+    /// Experience a swarm needs to grow new members
+    pub growth_threshold: i64,
+    /// Ticks a bullet flies for
+    pub bullet_lifetime: i64,
+    /// Ticks between shots
+    pub fire_cooldown: i64,
+    /// Ticks between formation changes
+    pub formation_cooldown: i64,
+    /// Cycles a program can use each tick
+    pub cycle_budget: usize,
+}
+
+/// Default rules
+impl Default for Rules {
+    /// The rules the game was designed with
+    fn default() -> Self {
+        Rules {
+            max_program_length: MAX_NUM_COMMANDS,
+            initial_swarm_size: INITIAL_SWARM_SIZE,
+            max_swarm_size: MAX_SWARM_SIZE,
+            growth_threshold: Swarm::GROWTH_THRESHOLD,
+            bullet_lifetime: Bullet::LIFETIME,
+            fire_cooldown: Swarm::FIRE_COOLDOWN,
+            formation_cooldown: Swarm::FORMATION_COOLDOWN,
+            cycle_budget: Swarm::CYCLE_BUDGET,
+        }
+    }
+}
+
+/// Functions for the rules
+impl Rules {
+    /// Checks the rules can run a game
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if self.max_program_length == 0 {
+            return Err(GenericError::new("Programs must be allowed at least 1 instruction".into()));
+        }
+        if self.cycle_budget == 0 {
+            return Err(GenericError::new("Programs must be allowed at least 1 cycle per tick".into()));
+        }
+        if self.bullet_lifetime <= 0 {
+            return Err(GenericError::new("Bullets must last at least 1 tick".into()));
+        }
+        if self.growth_threshold <= 0 {
+            return Err(GenericError::new("The growth threshold must be at least 1".into()));
+        }
+        if self.fire_cooldown < 0 {
+            return Err(GenericError::new("The fire cooldown can't be negative".into()));
+        }
+        if self.formation_cooldown < 0 {
+            return Err(GenericError::new("The formation cooldown can't be negative".into()));
+        }
+        // Members start in the slots around the center of the swarm
+        let slots = Swarm::calculate_offsets(1.0).len();
+        if self.max_swarm_size > slots {
+            return Err(GenericError::new(format!("Swarms can have at most {} members", slots)));
+        }
+        if self.initial_swarm_size > self.max_swarm_size {
+            return Err(GenericError::new(
+                "Swarms can't start with more members than their maximum".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Checks a world of the given size has room to place swarms
+/// Swarms are placed at least 50 away from the edges
+pub fn validate_size(width: f32, height: f32) -> Result<(), GenericError> {
+    if !(width > 100.0 && height > 100.0) {
+        return Err(GenericError::new("The world must be larger than 100x100".into()));
+    }
+    Ok(())
+}
+
+/// Settings for a game server
+/// Settings missing from a config file keep their defaults
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address for the websocket to listen on
+    pub hostname: String,
+    /// Port for the websocket to listen on
+    pub port: u16,
+    /// Number of server updates per second
+    pub update_freq: u64,
+    /// Ticks between world states pushed to clients
+    pub broadcast_interval: u64,
+    /// Most ticks between whole world states sent to a client, with only changes in between
+    pub keyframe_interval: u64,
+    /// Width of the game world
+    pub width: f32,
+    /// Height of the game world
+    pub height: f32,
+    /// Most players that can be connected at once
+    pub max_players: usize,
+    /// Where the world is saved so it survives restarts, if anywhere
+    /// Not saved by default, so servers in one process don't share a file
+    pub snapshot_path: Option<PathBuf>,
+    /// Where a replay of everything players do is saved, if anywhere
+    /// Saved along with the snapshots, and resumed with them if it matches the saved world
+    pub replay_path: Option<PathBuf>,
+    /// The balance of the game
+    pub rules: Rules,
+}
+
+/// Default server settings
+impl Default for ServerConfig {
+    /// Listens on port 8080 of every interface with a 1600x900 world
+    fn default() -> Self {
+        ServerConfig {
+            hostname: "0.0.0.0".into(),
+            port: 8080,
+            update_freq: 60,
+            broadcast_interval: 1,
+            keyframe_interval: 60,
+            width: 1600.0,
+            height: 900.0,
+            max_players: 100,
+            snapshot_path: None,
+            replay_path: None,
+            rules: Rules::default(),
+        }
+    }
+}
+
+/// Functions for server settings
+impl ServerConfig {
+    /// Reads settings from a JSON file
+    pub fn load(path: &Path) -> Result<Self, GenericError> {
+        File::open(path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| serde_json::from_reader(file).map_err(|error| error.to_string()))
+            .map_err(|error| GenericError::new(format!("{}: {}", path.display(), error)))
+    }
+    /// Checks the settings can run a game
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if self.update_freq == 0 {
+            return Err(GenericError::new("The tick rate must be at least 1".into()));
+        }
+        if self.broadcast_interval == 0 {
+            return Err(GenericError::new("The broadcast interval must be at least 1 tick".into()));
+        }
+        if self.keyframe_interval == 0 {
+            return Err(GenericError::new("The keyframe interval must be at least 1 tick".into()));
+        }
+        validate_size(self.width, self.height)?;
+        self.rules.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_config() {
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"port": 9000, "rules": {"fire_cooldown": 4}}"#).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.hostname, "0.0.0.0");
+        assert_eq!(config.rules.fire_cooldown, 4);
+        assert_eq!(config.rules.bullet_lifetime, Bullet::LIFETIME);
+        assert!(config.validate().is_ok());
+
+        let mut config = ServerConfig::default();
+        config.rules.initial_swarm_size = config.rules.max_swarm_size + 1;
+        assert!(config.validate().is_err());
+        let mut config = ServerConfig::default();
+        config.rules.bullet_lifetime = 0;
+        assert!(config.validate().is_err());
+        let mut config = ServerConfig::default();
+        config.rules.growth_threshold = -5;
+        assert!(config.validate().is_err());
+        // Cooldowns of 0 allow an action every tick, but can't go below that
+        let mut config = ServerConfig::default();
+        config.rules.fire_cooldown = 0;
+        config.rules.formation_cooldown = 0;
+        assert!(config.validate().is_ok());
+        config.rules.fire_cooldown = -1;
+        assert!(config.validate().is_err());
+        let mut config = ServerConfig::default();
+        config.rules.formation_cooldown = -1;
+        assert!(config.validate().is_err());
+        let mut config = ServerConfig::default();
+        config.rules.cycle_budget = 0;
+        assert!(config.validate().is_err());
+    }
+}
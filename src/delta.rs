@@ -0,0 +1,283 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+use entity::{BulletState, SwarmState};
+use rpc::ResponseMessage;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use world::WorldState;
+
+/// Most unacknowledged states kept for a client
+/// Clients that fall further behind are sent a keyframe
+/// States sent to several clients are shared, so this is mostly the same states for each
+const MAX_HISTORY: usize = 120;
+
+/// The changes between two world states
+/// Entities are keyed by player ID for swarms and by bullet ID for bullets
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WorldDelta {
+    /// Tick of the state the changes apply to
+    pub base: u64,
+    /// Tick of the state the changes lead to
+    pub tick: u64,
+    /// Swarms created or changed since the base
+    pub swarms: BTreeMap<usize, SwarmState>,
+    /// Swarms removed since the base
+    pub removed_swarms: Vec<usize>,
+    /// Bullets created or changed since the base
+    pub bullets: Vec<BulletState>,
+    /// Bullets removed since the base
+    pub removed_bullets: Vec<u64>,
+}
+
+/// Functions for a world delta
+impl WorldDelta {
+    /// Finds the changes from one state to another
+    pub fn new(base: &WorldState, state: &WorldState) -> Self {
+        let old_bullets: BTreeMap<u64, &BulletState> =
+            base.bullets.iter().map(|bullet| (bullet.id, bullet)).collect();
+        let new_bullets: BTreeMap<u64, &BulletState> =
+            state.bullets.iter().map(|bullet| (bullet.id, bullet)).collect();
+        WorldDelta {
+            base: base.tick,
+            tick: state.tick,
+            swarms: state
+                .swarms
+                .iter()
+                .filter(|&(id, swarm)| base.swarms.get(id) != Some(swarm))
+                .map(|(id, swarm)| (*id, swarm.clone()))
+                .collect(),
+            removed_swarms: base.swarms
+                .keys()
+                .filter(|id| !state.swarms.contains_key(*id))
+                .cloned()
+                .collect(),
+            bullets: state
+                .bullets
+                .iter()
+                .filter(|bullet| old_bullets.get(&bullet.id) != Some(bullet))
+                .cloned()
+                .collect(),
+            removed_bullets: old_bullets
+                .keys()
+                .filter(|id| !new_bullets.contains_key(*id))
+                .cloned()
+                .collect(),
+        }
+    }
+    /// Applies the changes to the state they were made from
+    /// This is what clients do with each delta
+    /// Bullets end up ordered by ID
+    pub fn apply(&self, base: &WorldState) -> WorldState {
+        let mut state = base.clone();
+        state.tick = self.tick;
+        for id in self.removed_swarms.iter() {
+            state.swarms.remove(id);
+        }
+        for (id, swarm) in self.swarms.iter() {
+            state.swarms.insert(*id, swarm.clone());
+        }
+        // Bullets are looked up by ID, since there can be thousands of them
+        let mut bullets: BTreeMap<u64, BulletState> =
+            base.bullets.iter().map(|bullet| (bullet.id, *bullet)).collect();
+        for id in self.removed_bullets.iter() {
+            bullets.remove(id);
+        }
+        for bullet in self.bullets.iter() {
+            bullets.insert(bullet.id, *bullet);
+        }
+        state.bullets = bullets.into_iter().map(|(_, bullet)| bullet).collect();
+        state
+    }
+}
+
+/// Decides what to send a client each tick: a whole state or the changes since one it has
+/// Clients acknowledge the states they receive, and changes are always from the latest of those
+/// Frames dropped on the way only cost bandwidth, since nothing is based on them until acknowledged
+#[derive(Clone, Debug)]
+pub struct DeltaEncoder {
+    /// Most ticks between whole states
+    keyframe_interval: u64,
+    /// States sent since the last acknowledged one, oldest first
+    /// The acknowledged state stays at the front as the base for changes
+    sent: VecDeque<Arc<WorldState>>,
+    /// Tick of the last state the client acknowledged
+    acknowledged: Option<u64>,
+    /// Tick of the last whole state sent
+    last_keyframe: Option<u64>,
+}
+
+/// Functions for a delta encoder
+impl DeltaEncoder {
+    /// Constructor for a client that has nothing yet
+    pub fn new(keyframe_interval: u64) -> Self {
+        DeltaEncoder {
+            keyframe_interval: keyframe_interval,
+            sent: VecDeque::new(),
+            acknowledged: None,
+            last_keyframe: None,
+        }
+    }
+    /// Records that the client received the state from a tick
+    /// Acknowledgements of states that weren't sent, or are older than the last one, are ignored
+    pub fn acknowledge(&mut self, tick: u64) {
+        if self.acknowledged.map_or(false, |acknowledged| tick <= acknowledged)
+            || !self.sent.iter().any(|state| state.tick == tick)
+        {
+            return;
+        }
+        self.acknowledged = Some(tick);
+        // Older states will never be a base again
+        while self.sent.front().map_or(false, |state| state.tick < tick) {
+            self.sent.pop_front();
+        }
+    }
+    /// Tick of the state the changes to a state from the given tick would be sent from
+    /// None if the client is due a whole state
+    pub fn base(&self, tick: u64) -> Option<u64> {
+        let keyframe_due = self.last_keyframe
+            .map_or(true, |last_keyframe| tick >= last_keyframe + self.keyframe_interval);
+        // Changes can only be sent from a state the client has
+        match self.acknowledged {
+            Some(acknowledged) if !keyframe_due => self.sent
+                .iter()
+                .find(|sent| sent.tick == acknowledged)
+                .map(|sent| sent.tick),
+            _ => None,
+        }
+    }
+    /// Records that the client was sent a state, as changes from the given base or whole
+    /// Used instead of encode when the message was already built for another client
+    pub fn record(&mut self, state: Arc<WorldState>, base: Option<u64>) {
+        if base.is_none() {
+            self.last_keyframe = Some(state.tick);
+        }
+        self.sent.push_back(state);
+        // Forget the oldest states of clients that stop acknowledging
+        while self.sent.len() > MAX_HISTORY {
+            self.sent.pop_front();
+        }
+    }
+    /// The message to send the client for a state
+    /// The state can be shared with other clients' encoders, which saves keeping copies of it
+    pub fn encode(&mut self, state: Arc<WorldState>) -> ResponseMessage {
+        let base = self.base(state.tick);
+        let message = match base.and_then(|base| self.sent.iter().find(|sent| sent.tick == base)) {
+            Some(base) => ResponseMessage::DELTA(WorldDelta::new(base, &state)),
+            None => ResponseMessage::WORLD((*state).clone()),
+        };
+        self.record(state, base);
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpc::Response;
+    use world::World;
+
+    #[test]
+    fn test_delta() {
+        let mut world = World::new(800.0, 600.0).with_seed(3);
+        for id in 0..3 {
+            world.add_player(id);
+        }
+        world.update_program(0, "MOVE\nFIRE".parse().unwrap());
+        for _ in 0..10 {
+            world.update();
+        }
+        let base = world.get_state();
+        world.remove_player(2);
+        world.add_player(3);
+        for _ in 0..5 {
+            world.update();
+        }
+        let mut state = world.get_state();
+        let delta = WorldDelta::new(&base, &state);
+        assert_eq!(delta.removed_swarms, vec![2]);
+        assert!(delta.swarms.contains_key(&3));
+        assert!(!delta.bullets.is_empty());
+        // Bullets can end up in a different order
+        let mut applied = delta.apply(&base);
+        applied.bullets.sort_by_key(|bullet| bullet.id);
+        state.bullets.sort_by_key(|bullet| bullet.id);
+        assert_eq!(applied, state);
+        // Nothing is sent for entities that haven't changed
+        let unchanged = WorldDelta::new(&state, &state);
+        assert!(unchanged.swarms.is_empty() && unchanged.bullets.is_empty());
+        assert!(unchanged.removed_swarms.is_empty() && unchanged.removed_bullets.is_empty());
+    }
+
+    #[test]
+    fn test_encoder() {
+        let mut world = World::new(800.0, 600.0).with_seed(3);
+        world.add_player(0);
+        world.update_program(0, "MOVE".parse().unwrap());
+        let mut encoder = DeltaEncoder::new(4);
+        // The base of each message, or None for keyframes
+        let mut bases: Vec<Option<u64>> = Vec::new();
+        for tick in 1..11 {
+            world.update();
+            // The client acknowledges every other state it receives
+            if tick % 2 == 0 {
+                encoder.acknowledge(tick - 1);
+            }
+            bases.push(match encoder.encode(Arc::new(world.get_state())) {
+                ResponseMessage::DELTA(delta) => Some(delta.base),
+                _ => None,
+            });
+        }
+        assert_eq!(
+            bases,
+            vec![None, Some(1), Some(1), Some(3), None, Some(5), Some(5), Some(7), None, Some(9)]
+        );
+        // States that were never sent can't be a base
+        encoder.acknowledge(50);
+        world.update();
+        match encoder.encode(Arc::new(world.get_state())) {
+            ResponseMessage::DELTA(delta) => assert_eq!((delta.base, delta.tick), (9, 11)),
+            _ => panic!("Expected a delta"),
+        }
+    }
+
+    #[test]
+    fn test_delta_size() {
+        // Swarms that hunt, wander, wait and guard, as in a typical game
+        let programs = [
+            "AIM NEAREST\nMOVE\nFIRE\nTURN 15",
+            "MOVE\nTURN 5",
+            "NOOP",
+            "IF ENEMY_NEAR 200\nFIRE\nEND\nNOOP",
+        ];
+        let mut world = World::new(1600.0, 900.0).with_seed(9);
+        for id in 0..12 {
+            world.add_player(id);
+            world.update_program(id, programs[id % programs.len()].parse().unwrap());
+        }
+        for _ in 0..300 {
+            world.update();
+        }
+        let base = world.get_state();
+        world.update();
+        let state = world.get_state();
+        let delta = WorldDelta::new(&base, &state);
+        assert!(delta.swarms.len() < state.swarms.len());
+        // Bullets move every tick, so most of what is saved is swarms that didn't change
+        let delta = Response::new(ResponseMessage::DELTA(delta)).serialize().unwrap();
+        let keyframe = Response::new(ResponseMessage::WORLD(state)).serialize().unwrap();
+        assert!(delta.len() < keyframe.len());
+    }
+}
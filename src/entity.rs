@@ -13,50 +13,114 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
-use swarm_language::{Formation, SwarmCommand, SwarmProgram};
-use world::World;
+use swarm_language::{Condition, Event, Formation, Operand, RuntimeFault, SwarmCommand,
+                     SwarmProgram, MAX_TURN};
+use vm::{self, Sensors};
+use world::{World, WorldView};
 use std::f32;
 
 /// The initial size of a swarm
-const INITIAL_SWARM_SIZE: usize = 10;
+pub const INITIAL_SWARM_SIZE: usize = 10;
 /// The maximum size of a swarm
-const MAX_SWARM_SIZE: usize = 20;
+pub const MAX_SWARM_SIZE: usize = 20;
 
 /// Represents a player's swarm
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Swarm {
     /// X position
     pub x: f32,
     /// Y position
     pub y: f32,
     /// Direction the swarm is facing
-    #[serde(skip_serializing)]
     pub direction: f32,
     /// Members of the swarm
     pub members: Vec<SwarmMember>,
     /// Offsets
-    #[serde(skip_serializing)]
     pub offsets: Vec<(f32, f32)>,
     /// Color of the swarm
     pub color: (u8, u8, u8),
     /// Experience gained by the swarm
     pub experience: i64,
-    /// More units are earned after 300 experience is full
+    /// More units are earned when this reaches the growth threshold
     pub pool: i64,
     /// Fire cooldown in ticks
-    #[serde(skip_serializing)]
     pub fire_cooldown: i64,
     /// Formation cooldown in ticks
-    #[serde(skip_serializing)]
     pub formation_cooldown: i64,
     /// Program used to execute the swarm
-    #[serde(skip_serializing)]
     pub program: SwarmProgram,
+    /// How the swarm has fared in the match so far
+    pub stats: SwarmStats,
 }
+
+/// Statistics kept for each swarm over a match
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwarmStats {
+    /// Enemy members destroyed by the swarm's bullets
+    pub kills: usize,
+    /// Damage dealt to other swarms
+    pub damage_dealt: i64,
+    /// Damage taken from other swarms
+    pub damage_taken: i64,
+    /// The tick the swarm lost its last member, if it has
+    pub eliminated_at: Option<u64>,
+}
+
+/// What clients are sent about a swarm
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SwarmState {
+    /// X position
+    pub x: f32,
+    /// Y position
+    pub y: f32,
+    /// Members of the swarm
+    pub members: Vec<MemberState>,
+    /// Color of the swarm
+    pub color: (u8, u8, u8),
+    /// Experience gained by the swarm
+    pub experience: i64,
+    /// Progress toward the next new member
+    pub pool: i64,
+    /// The line that will run next and any fault
+    pub program: ProgramStatus,
+}
+
+/// What clients are sent about a member of a swarm
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct MemberState {
+    /// X position
+    pub x: f32,
+    /// Y position
+    pub y: f32,
+    /// Direction
+    pub direction: f32,
+    /// Health
+    pub health: i32,
+}
+
+/// What clients are sent about a swarm's program
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ProgramStatus {
+    /// Source line of the next command
+    pub line: Option<usize>,
+    /// Error that stopped the program, if any
+    pub fault: Option<RuntimeFault>,
+}
+
 /// Functions for a swarm
 impl Swarm {
     /// Swarm speed
     const UPDATE_DISTANCE: f32 = 5.0;
+    /// Default ticks between shots
+    pub const FIRE_COOLDOWN: i64 = 10; // 60fps * 0.5 seconds
+    /// Default ticks between formation changes
+    pub const FORMATION_COOLDOWN: i64 = 30;
+    /// Default experience needed to grow new members
+    pub const GROWTH_THRESHOLD: i64 = 300;
+    /// Space between members of a formation when none is given
+    pub const DEFAULT_SPACING: f32 = 30.0;
+    /// Default number of cycles the program can use per tick
+    pub const CYCLE_BUDGET: usize = 16;
     /// Constructor
     pub fn new(x: f32, y: f32, num_members: usize) -> Self {
         // Build the offsets
@@ -84,6 +148,7 @@ impl Swarm {
                 SwarmCommand::TURN(10.0),
                 SwarmCommand::FIRE,
             ]),
+            stats: SwarmStats::default(),
         }
     }
     /// Builds a swarm of N members
@@ -104,29 +169,52 @@ impl Swarm {
         self.pool += amt;
     }
 
+    /// What clients are sent about the swarm
+    pub fn state(&self) -> SwarmState {
+        SwarmState {
+            x: self.x,
+            y: self.y,
+            members: self.members
+                .iter()
+                .map(|member| MemberState {
+                    x: member.x,
+                    y: member.y,
+                    direction: member.direction,
+                    health: member.health,
+                })
+                .collect(),
+            color: self.color,
+            experience: self.experience,
+            pool: self.pool,
+            program: ProgramStatus {
+                line: self.program.current_line(),
+                fault: self.program.fault,
+            },
+        }
+    }
+
     /// Supplementary function to add color to a swarm. Typically used with the constructor
     pub fn with_color(mut self, color: (u8, u8, u8)) -> Self {
         self.color = color;
         self
     }
     /// Performs 1 tick
-    pub fn update(
-        &mut self,
-        swarm_id: usize,
-        world_width: f32,
-        world_height: f32,
-        bullets: &mut Vec<Bullet>,
-    ) {
+    pub fn update(&mut self, swarm_id: usize, world: &WorldView, bullets: &mut Vec<Bullet>) {
+        let world_width = world.width;
+        let world_height = world.height;
         // lose exp on death
         if self.members.len() <= 0 {
             self.experience = 0;
         }
 
         // level up
-        if self.pool >= 300 {
-            self.pool -= 300;
+        if self.pool >= world.rules.growth_threshold {
+            self.pool -= world.rules.growth_threshold;
 
             for i in 0..self.members.len() {
+                if self.members.len() >= world.rules.max_swarm_size {
+                    break;
+                }
                 if !(self.members[i].x == self.offsets[self.offsets.len() - (1 + i)].0 &&
                          self.members[i].y == self.offsets[self.offsets.len() - (1 + i)].1)
                 {
@@ -139,8 +227,22 @@ impl Swarm {
         }
 
 
-        if self.program.commands.len() != 0 {
-            match self.program.commands[self.program.program_counter] {
+        // Faulted programs stop until a new program is installed
+        let faulted = self.program.fault.is_some();
+        // Run the program until it reaches a command that acts on the world
+        let command = {
+            let sensors = SwarmSensors {
+                swarm_id: swarm_id,
+                x: self.x,
+                y: self.y,
+                direction: self.direction,
+                members: &self.members,
+                world: world,
+            };
+            vm::run(&mut self.program, &sensors, world.rules.cycle_budget)
+        };
+        if let Some(command) = command {
+            match command {
                 SwarmCommand::MOVE => {
                     // When within EPSILON of edge of the world, bounce off it
                     const EPSILON: f32 = 10.0;
@@ -149,6 +251,7 @@ impl Swarm {
                         self.y + EPSILON >= world_height
                     {
                         self.direction = -self.direction;
+                        self.program.interrupt(Event::WALL);
                     }
 
                     // Update the x and y position
@@ -163,6 +266,8 @@ impl Swarm {
                         self.y + EPSILON < world_height
                     {
                         self.x += Swarm::UPDATE_DISTANCE;
+                    } else {
+                        self.program.interrupt(Event::WALL);
                     }
                 }
                 SwarmCommand::RIGHT => {
@@ -173,6 +278,8 @@ impl Swarm {
                         self.y + EPSILON < world_height
                     {
                         self.x -= Swarm::UPDATE_DISTANCE;
+                    } else {
+                        self.program.interrupt(Event::WALL);
                     }
                 }
                 SwarmCommand::UP => {
@@ -183,6 +290,8 @@ impl Swarm {
                         self.y + EPSILON < world_height
                     {
                         self.y -= Swarm::UPDATE_DISTANCE;
+                    } else {
+                        self.program.interrupt(Event::WALL);
                     }
                 }
                 SwarmCommand::DOWN => {
@@ -193,6 +302,8 @@ impl Swarm {
                         self.y + EPSILON < world_height
                     {
                         self.y += Swarm::UPDATE_DISTANCE;
+                    } else {
+                        self.program.interrupt(Event::WALL);
                     }
                 }
 
@@ -200,46 +311,58 @@ impl Swarm {
                     // TODO maybe change the fire_cooldown scalar depending
                     // on what kind of weapon is fired?
                     if self.fire_cooldown == 0 {
-                        self.fire(swarm_id, bullets);
-                        self.fire_cooldown = 10; // 60fps * 0.5 seconds
+                        self.fire(swarm_id, world.rules.bullet_lifetime, bullets);
+                        self.fire_cooldown = world.rules.fire_cooldown;
                     }
                 }
-                SwarmCommand::TURN(turn_amt) => {
-                    // turn logic
-                    self.direction += turn_amt;
-                    // Keep direction and program counter within their bounds
-                    self.direction %= 360.0;
-                    for member in self.members.iter_mut() {
-                        member.direction += turn_amt;
-                        member.direction %= 360.0;
+                SwarmCommand::TURN(turn_amt) => self.turn(turn_amt),
+                SwarmCommand::AIM(target) => {
+                    if let Some(target) = world.find_target(swarm_id, self.x, self.y, target) {
+                        // Angle to the target, using the same axes as MOVE
+                        let heading = (self.y - target.y).atan2(target.x - self.x).to_degrees();
+                        // Take the shortest way around
+                        let mut turn_amt = (heading - self.direction) % 360.0;
+                        if turn_amt > 180.0 {
+                            turn_amt -= 360.0;
+                        } else if turn_amt < -180.0 {
+                            turn_amt += 360.0;
+                        }
+                        self.turn(turn_amt.max(-MAX_TURN).min(MAX_TURN));
                     }
                 }
 
-                SwarmCommand::FORMATION(formation) => {
+                SwarmCommand::FORMATION(formation, spacing) => {
                     if self.formation_cooldown == 0 {
-                        match formation {
-                            Formation::GATHER => {
-                                for (index, member) in self.members.iter_mut().enumerate() {
-                                    member.x = self.offsets[index].0;
-                                    member.y = self.offsets[index].1;
-                                }
-                            }
-                            Formation::SPREAD => {
-                                for (index, member) in self.members.iter_mut().enumerate() {
-                                    member.x = self.offsets[self.offsets.len() - (1 + index)].0;
-                                    member.y = self.offsets[self.offsets.len() - (1 + index)].1;
-                                }
-                            }
-                        };
-                        self.formation_cooldown = 30
+                        let slots = Swarm::formation_offsets(
+                            formation,
+                            self.members.len(),
+                            self.direction,
+                            spacing.unwrap_or(Swarm::DEFAULT_SPACING),
+                        );
+                        // Members make their way to the new slots over the next ticks
+                        for (member, &(x, y)) in self.members.iter_mut().zip(slots.iter()) {
+                            member.target_x = x;
+                            member.target_y = y;
+                        }
+                        self.formation_cooldown = world.rules.formation_cooldown
                     }
                 }
                 SwarmCommand::NOOP => {}
+                // Handled by the VM
+                SwarmCommand::SET(_, _) |
+                SwarmCommand::ADD(_, _) |
+                SwarmCommand::SUB(_, _) |
+                SwarmCommand::MUL(_, _) |
+                SwarmCommand::CMP(_, _) => {}
             }
-
-            // Update program_counter to point to next command
-            self.program.program_counter += 1;
-            self.program.program_counter %= self.program.commands.len();
+        }
+        if !faulted {
+            if let Some(fault) = self.program.fault {
+                warn!("Program for swarm {} stopped: {}", swarm_id, fault);
+            }
+        }
+        for member in self.members.iter_mut() {
+            member.update();
         }
         self.fire_cooldown -= 1;
         self.formation_cooldown -= 1;
@@ -251,7 +374,20 @@ impl Swarm {
         }
     }
 
-    pub fn fire(&self, swarm_id: usize, bullets: &mut Vec<Bullet>) {
+    /// Rotates the swarm and its members some number of degrees
+    pub fn turn(&mut self, turn_amt: f32) {
+        // turn logic
+        self.direction += turn_amt;
+        // Keep direction within its bounds
+        self.direction %= 360.0;
+        for member in self.members.iter_mut() {
+            member.direction += turn_amt;
+            member.direction %= 360.0;
+        }
+    }
+
+    /// Fires a bullet from each member that flies for the given number of ticks
+    pub fn fire(&self, swarm_id: usize, lifetime: i64, bullets: &mut Vec<Bullet>) {
         // spawn bullet with velocity vector
         for member in &self.members {
             let new_bullet: Bullet = Bullet::new(
@@ -259,11 +395,102 @@ impl Swarm {
                 self.x + member.x,
                 self.y + member.y,
                 self.direction,
-            );
+            ).with_lifetime(lifetime);
             bullets.push(new_bullet);
         }
     }
 
+    /// Distance from the center of the swarm to its furthest member
+    pub fn extent(&self) -> f32 {
+        self.members
+            .iter()
+            .map(|member| member.x.hypot(member.y))
+            .fold(0.0, f32::max)
+    }
+
+    /// Calculates the offset of each member in a formation
+    /// Formations are oriented so their front faces the given direction
+    pub fn formation_offsets(
+        formation: Formation,
+        num_members: usize,
+        direction: f32,
+        spacing: f32,
+    ) -> Vec<(f32, f32)> {
+        // Distance forward and to the side of the center for each member
+        let layout: Vec<(f32, f32)> = match formation {
+            Formation::GATHER | Formation::SPREAD => {
+                // Rings are the same from every direction, so they aren't rotated
+                let mut rings = Swarm::calculate_offsets(spacing);
+                // Add shells until every member has a slot
+                let mut shell = 4;
+                while rings.len() < num_members {
+                    for j in 0..(shell * 4) {
+                        let rads: f32 = (j as f32) * (f32::consts::PI / (2.0 * shell as f32));
+                        rings.push((
+                            shell as f32 * spacing * rads.cos(),
+                            shell as f32 * spacing * rads.sin(),
+                        ));
+                    }
+                    shell += 1;
+                }
+                // Gather fills the inner slots, spread fills the outer ones
+                if formation == Formation::SPREAD {
+                    rings.reverse();
+                }
+                rings.truncate(num_members);
+                return rings;
+            }
+            Formation::LINE => (0..num_members)
+                .map(|i| (0.0, (i as f32 - (num_members as f32 - 1.0) / 2.0) * spacing))
+                .collect(),
+            Formation::COLUMN => (0..num_members)
+                .map(|i| (((num_members as f32 - 1.0) / 2.0 - i as f32) * spacing, 0.0))
+                .collect(),
+            Formation::WEDGE => (0..num_members)
+                .map(|i| {
+                    // The leader is at the point, then pairs go back along each side
+                    let rank = ((i + 1) / 2) as f32;
+                    let side = if i % 2 == 0 { rank } else { -rank };
+                    (-rank * spacing, side * spacing)
+                })
+                .collect(),
+            Formation::CIRCLE => {
+                // Neighbours on the circle are spacing apart
+                let angle = 2.0 * f32::consts::PI / num_members as f32;
+                let radius = if num_members > 1 {
+                    spacing / (2.0 * (angle / 2.0).sin())
+                } else {
+                    0.0
+                };
+                (0..num_members)
+                    .map(|i| {
+                        let rads = angle * i as f32;
+                        (radius * rads.cos(), radius * rads.sin())
+                    })
+                    .collect()
+            }
+            Formation::BOX => {
+                let columns = (num_members as f32).sqrt().ceil() as usize;
+                let rows = (num_members + columns.max(1) - 1) / columns.max(1);
+                (0..num_members)
+                    .map(|i| {
+                        let (row, column) = (i / columns, i % columns);
+                        (
+                            ((rows as f32 - 1.0) / 2.0 - row as f32) * spacing,
+                            (column as f32 - (columns as f32 - 1.0) / 2.0) * spacing,
+                        )
+                    })
+                    .collect()
+            }
+        };
+        // Rotate the layout to face the direction, using the same axes as MOVE
+        let (sin, cos) = direction.to_radians().sin_cos();
+        layout
+            .into_iter()
+            .map(|(forward, side)| (forward * cos + side * sin, side * cos - forward * sin))
+            .collect()
+    }
+
     // Calculates the offset for a number of position parameters
     pub fn calculate_offsets(radius: f32) -> Vec<(f32, f32)> {
         // Initialize list with origin offset (0,0)
@@ -331,8 +558,56 @@ fn test_offset_calc() {
     }
 }
 
+/// What a swarm's program can sense while it runs
+pub struct SwarmSensors<'a> {
+    /// ID of the swarm, so it doesn't sense itself
+    pub swarm_id: usize,
+    /// X position of the swarm
+    pub x: f32,
+    /// Y position of the swarm
+    pub y: f32,
+    /// Direction the swarm is facing
+    pub direction: f32,
+    /// Members of the swarm
+    pub members: &'a [SwarmMember],
+    /// Everything else in the world
+    pub world: &'a WorldView,
+}
+
+/// Lets swarm programs sense the world
+impl<'a> Sensors for SwarmSensors<'a> {
+    /// Evaluates a condition against the swarm's surroundings
+    fn check(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::ENEMY_NEAR(distance) => {
+                match self.world.nearest_enemy_distance(self.swarm_id, self.x, self.y) {
+                    Some(nearest) => nearest <= distance,
+                    None => false,
+                }
+            }
+            Condition::BULLET_NEAR(distance) => {
+                self.world.bullets_within(self.swarm_id, self.x, self.y, distance) > 0
+            }
+            Condition::HEALTH_LOW(num_members) => self.members.len() < num_members,
+            Condition::WALL_NEAR(distance) => self.world.wall_distance(self.x, self.y) <= distance,
+            // Comparisons are handled by the VM
+            Condition::LESS | Condition::EQUAL | Condition::GREATER => false,
+        }
+    }
+
+    /// Reads the value of an operand of a register command
+    fn read(&self, operand: Operand) -> f64 {
+        match operand {
+            Operand::HEADING => self.direction as f64,
+            Operand::MEMBERS => self.members.len() as f64,
+            // Registers and constants are handled by the VM
+            Operand::REGISTER(_) | Operand::CONSTANT(_) => 0.0,
+        }
+    }
+}
+
 /// Represents a member of a swarm
-#[derive(Clone, Copy, Debug, Serialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct SwarmMember {
     /// X position
     pub x: f32,
@@ -342,22 +617,47 @@ pub struct SwarmMember {
     pub direction: f32,
     /// Health
     pub health: i32,
+    /// X position of the member's slot in the formation
+    pub target_x: f32,
+    /// Y position of the member's slot in the formation
+    pub target_y: f32,
 }
 /// Functions for SwarmMember
 impl SwarmMember {
+    /// Distance a member can move toward its slot each tick
+    pub const SPEED: f32 = 3.0;
     pub fn new(x: f32, y: f32) -> Self {
         SwarmMember {
             x: x,
             y: y,
             direction: 0.0,
             health: 5,
+            target_x: x,
+            target_y: y,
+        }
+    }
+
+    /// Moves the member toward its slot in the formation
+    pub fn update(&mut self) {
+        let (dx, dy) = (self.target_x - self.x, self.target_y - self.y);
+        let distance = dx.hypot(dy);
+        if distance <= SwarmMember::SPEED {
+            self.x = self.target_x;
+            self.y = self.target_y;
+        } else {
+            self.x += dx / distance * SwarmMember::SPEED;
+            self.y += dy / distance * SwarmMember::SPEED;
         }
     }
 }
 
 /// Represents a bullet
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Bullet {
+    /// Stable ID for clients to track the bullet by, assigned by the world
+    /// Bullets from snapshots saved before IDs existed all have 0
+    #[serde(default)]
+    pub id: u64,
     /// ID of the player that fired this bullet
     /// This is used so the player can't shoot themself
     pub owner: usize,
@@ -368,20 +668,35 @@ pub struct Bullet {
     /// Direction in degrees
     pub direction: f32,
     /// Duration of bullet in ticks; counts down to 0
-    #[serde(skip_serializing)]
     pub duration: i64,
 }
 
+/// What clients are sent about a bullet
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct BulletState {
+    /// Stable ID of the bullet
+    pub id: u64,
+    /// ID of the player that fired this bullet
+    pub owner: usize,
+    /// X position
+    pub x: f32,
+    /// Y position
+    pub y: f32,
+    /// Direction in degrees
+    pub direction: f32,
+}
+
 /// Functions for a bullet
 impl Bullet {
     /// Bullet speed
     const UPDATE_DISTANCE: f32 = 5.0;
     /// Default lifetime of bullet
-    const LIFETIME: i64 = 90;
+    pub const LIFETIME: i64 = 90;
     /// Constructor
     // TODO: add arguments
     pub fn new(owner: usize, x: f32, y: f32, direction: f32) -> Self {
         Bullet {
+            id: 0,
             owner: owner,
             x: x,
             y: y,
@@ -389,6 +704,21 @@ impl Bullet {
             duration: Bullet::LIFETIME,
         }
     }
+    /// Supplementary function to set how many ticks a bullet flies for
+    pub fn with_lifetime(mut self, lifetime: i64) -> Self {
+        self.duration = lifetime;
+        self
+    }
+    /// What clients are sent about the bullet
+    pub fn state(&self) -> BulletState {
+        BulletState {
+            id: self.id,
+            owner: self.owner,
+            x: self.x,
+            y: self.y,
+            direction: self.direction,
+        }
+    }
 
     /// Performs 1 tick
     pub fn update(&mut self) {
@@ -406,6 +736,24 @@ impl Bullet {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use swarm_language::MAX_CALL_DEPTH;
+
+    /// A swarm with some members running a program, in the middle of an empty world
+    /// Enemies can be added to the world before the swarm runs
+    fn setup(num_members: usize, source: &str) -> (World, Swarm) {
+        let world: World = World::new(1000.0, 1000.0);
+        let mut swarm = Swarm::new(500.0, 500.0, num_members);
+        swarm.program = source.parse().unwrap();
+        (world, swarm)
+    }
+
+    /// Runs a swarm's program for some ticks
+    fn run(swarm: &mut Swarm, world: &mut World, ticks: usize) {
+        for _ in 0..ticks {
+            swarm.update(0, &world.view(), &mut world.bullets);
+        }
+    }
+
     #[test]
     /// This test will start at the origin with 0 degrees, move, turn 45 degrees
     /// then move.  This will happen four times, and should return to the original
@@ -415,28 +763,24 @@ mod tests {
         let origin_x: f32 = 50.0;
         let origin_y: f32 = 50.0;
         let mut swarm = Swarm::new(origin_x, origin_y, 1);
-        swarm.program.commands.clear();
+        let mut commands: Vec<SwarmCommand> = Vec::new();
         let move_command: SwarmCommand = SwarmCommand::MOVE;
         let turn_command: SwarmCommand = SwarmCommand::TURN(-45.0);
         // 16 steps to complete move turn pairs at 45 degrees
         let num_steps: usize = 16;
         // append commands to program
         for i in 0..num_steps {
-            swarm.program.commands.push(turn_command);
-            swarm.program.commands.push(move_command);
+            commands.push(turn_command);
+            commands.push(move_command);
             println!("{:?} ", i);
         }
+        swarm.program = SwarmProgram::new(commands);
 
-        println!("{:?}", swarm.program.commands);
+        println!("{:?}", swarm.program.bytecode);
 
         // execute commands
         for _ in 0..num_steps {
-            swarm.update(
-                0,
-                test_world.width,
-                test_world.height,
-                &mut test_world.bullets,
-            );
+            swarm.update(0, &test_world.view(), &mut test_world.bullets);
             println!("x: {}, y: {}, dir: {}", swarm.x, swarm.y, swarm.direction);
         }
         //assert!(swarm.x - origin_x <= f32::EPSILON);
@@ -450,16 +794,10 @@ mod tests {
         let mut swarm = Swarm::new(0.0, 0.0, 1);
         let swarm_id = 0;
         let turn_amt = -100.0;
-        swarm.program.commands.push(SwarmCommand::TURN(turn_amt));
-        swarm.program.commands.push(SwarmCommand::MOVE);
+        swarm.program = SwarmProgram::new(vec![SwarmCommand::TURN(turn_amt), SwarmCommand::MOVE]);
 
         for _ in 0..2 {
-            swarm.update(
-                swarm_id,
-                test_world.width,
-                test_world.height,
-                &mut test_world.bullets,
-            );
+            swarm.update(swarm_id, &test_world.view(), &mut test_world.bullets);
             println!("x: {}, y: {}, dir: {}", swarm.x, swarm.y, swarm.direction);
         }
 
@@ -482,15 +820,128 @@ mod tests {
         let swarm_id: usize = 0;
         world.swarms.insert(swarm_id, Swarm::new(5.0, 5.0, 6));
         // TODO: handle the option better later
-        world.swarms.get_mut(&swarm_id).unwrap().program.commands = vec![SwarmCommand::FIRE];
+        world.swarms.get_mut(&swarm_id).unwrap().program = SwarmProgram::new(vec![SwarmCommand::FIRE]);
 
-        world.swarms.get_mut(&swarm_id).unwrap().update(
-            swarm_id,
-            world.width,
-            world.height,
-            &mut world.bullets,
-        );
+        let view = world.view();
+        world
+            .swarms
+            .get_mut(&swarm_id)
+            .unwrap()
+            .update(swarm_id, &view, &mut world.bullets);
 
         assert_eq!(world.bullets.len(), 6);
     }
+
+    #[test]
+    fn test_formations() {
+        let formations = [
+            Formation::GATHER,
+            Formation::SPREAD,
+            Formation::LINE,
+            Formation::COLUMN,
+            Formation::WEDGE,
+            Formation::CIRCLE,
+            Formation::BOX,
+        ];
+        for &formation in formations.iter() {
+            for num_members in 0..40 {
+                let slots = Swarm::formation_offsets(formation, num_members, 37.0, 15.0);
+                assert_eq!(slots.len(), num_members);
+                // Members never overlap
+                for (i, a) in slots.iter().enumerate() {
+                    for b in slots[i + 1..].iter() {
+                        assert!((a.0 - b.0).hypot(a.1 - b.1) >= 14.9, "{:?}", formation);
+                    }
+                }
+            }
+        }
+        // A line facing up the screen spreads out sideways
+        let slots = Swarm::formation_offsets(Formation::LINE, 3, 90.0, 20.0);
+        for (slot, &x) in slots.iter().zip([-20.0, 0.0, 20.0].iter()) {
+            assert!((slot.0 - x).abs() < 0.001 && slot.1.abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_formation_command() {
+        let (mut world, mut swarm) = setup(4, "FORMATION COLUMN 25");
+        let start: Vec<(f32, f32)> = swarm.members.iter().map(|member| (member.x, member.y)).collect();
+        run(&mut swarm, &mut world, 1);
+        // Members move toward their slots at a limited speed
+        for (member, &(x, y)) in swarm.members.iter().zip(start.iter()) {
+            assert!((member.x - x).hypot(member.y - y) <= SwarmMember::SPEED + 0.001);
+        }
+        run(&mut swarm, &mut world, 30);
+        let xs: Vec<f32> = swarm.members.iter().map(|member| member.x).collect();
+        assert_eq!(xs, vec![37.5, 12.5, -12.5, -37.5]);
+        assert_eq!(swarm.extent(), 37.5);
+    }
+
+    #[test]
+    fn test_jump() {
+        let (mut world, mut swarm) = setup(1, "TURN 10\nloop:\nMOVE\nJUMP loop");
+        run(&mut swarm, &mut world, 6);
+        // The turn in the prologue only runs once
+        assert_eq!(swarm.direction, 10.0);
+        assert_eq!(swarm.program.program_counter, 2);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let (mut world, mut swarm) = setup(1, "IF ENEMY_NEAR 100\nTURN 10\nELSE\nTURN -10\nEND\nNOOP");
+        world.swarms.insert(1, Swarm::new(500.0, 580.0, 1));
+        // The IF and JUMP chain into the commands after them
+        run(&mut swarm, &mut world, 2);
+        assert_eq!(swarm.direction, 10.0);
+        // Move the enemy out of range so the ELSE branch runs
+        world.swarms.get_mut(&1).unwrap().x = 900.0;
+        run(&mut swarm, &mut world, 2);
+        assert_eq!(swarm.direction, 0.0);
+    }
+
+    #[test]
+    fn test_registers() {
+        // Turn on every third tick
+        let (mut world, mut swarm) = setup(
+            1,
+            "ADD R0 1\nCMP R0 3\nIF EQUAL\nSET R0 0\nTURN 10\nSET F0 HEADING\nEND\nNOOP",
+        );
+        run(&mut swarm, &mut world, 2);
+        assert_eq!(swarm.direction, 0.0);
+        run(&mut swarm, &mut world, 2);
+        assert_eq!(swarm.direction, 10.0);
+        assert_eq!(swarm.program.int_registers[0], 0);
+        assert_eq!(swarm.program.float_registers[0], 10.0);
+    }
+
+    #[test]
+    fn test_aim() {
+        let (mut world, mut swarm) = setup(1, "AIM NEAREST");
+        // Enemy straight up the screen, which is 90 degrees
+        world.swarms.insert(1, Swarm::new(500.0, 100.0, 1));
+        run(&mut swarm, &mut world, 1);
+        // Turning is limited per command
+        assert_eq!(swarm.direction, MAX_TURN);
+        run(&mut swarm, &mut world, 5);
+        assert!((swarm.direction - 90.0).abs() < 0.001);
+        assert!((swarm.members[0].direction - 90.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_call() {
+        let (mut world, mut swarm) = setup(1, "CALL wiggle\nNOOP\nDEF wiggle\nTURN 10\nTURN 10\nEND");
+        // CALL and TURN, TURN, then RET and NOOP
+        run(&mut swarm, &mut world, 3);
+        assert_eq!(swarm.direction, 20.0);
+        assert_eq!(swarm.program.program_counter, 0);
+        assert!(swarm.program.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let (mut world, mut swarm) = setup(1, "CALL forever\nDEF forever\nCALL forever\nEND");
+        run(&mut swarm, &mut world, MAX_CALL_DEPTH + 5);
+        assert_eq!(swarm.program.fault, Some(RuntimeFault::STACK_OVERFLOW));
+        assert_eq!(swarm.program.call_stack.len(), MAX_CALL_DEPTH);
+    }
 }
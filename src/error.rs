@@ -51,3 +51,113 @@ impl Error for GenericError {
         None
     }
 }
+
+/// How serious a diagnostic is
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Severity {
+    /// The program can't be compiled
+    #[serde(rename = "error")]
+    ERROR,
+    /// The program compiles, but probably doesn't do what was intended
+    #[serde(rename = "warning")]
+    WARNING,
+}
+
+/// A problem found in the source of a swarm program
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    /// Line the problem is on, starting from 1
+    pub line: usize,
+    /// Column where the problem starts, counted in characters from 0
+    pub start: usize,
+    /// Column just past the end of the problem
+    pub end: usize,
+    /// How serious the problem is
+    pub severity: Severity,
+    /// Description of the problem
+    pub message: String,
+    /// A possible fix, if one is known
+    pub suggestion: Option<String>,
+}
+
+/// Functions for Diagnostic
+impl Diagnostic {
+    /// Constructor for an error spanning the given columns of a line
+    pub fn error(line: usize, start: usize, end: usize, message: String) -> Self {
+        Diagnostic {
+            line: line,
+            start: start,
+            end: end,
+            severity: Severity::ERROR,
+            message: message,
+            suggestion: None,
+        }
+    }
+    /// Constructor for a warning spanning the given columns of a line
+    pub fn warning(line: usize, start: usize, end: usize, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::WARNING,
+            ..Diagnostic::error(line, start, end, message)
+        }
+    }
+    /// Supplementary function to add a suggestion to a diagnostic
+    pub fn with_suggestion(mut self, suggestion: Option<String>) -> Self {
+        self.suggestion = suggestion;
+        self
+    }
+}
+
+/// Allows Diagnostic to be printed
+impl fmt::Display for Diagnostic {
+    /// Writes the diagnostic using a formatter
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "line {}: {}", self.line, self.message)?;
+        match self.suggestion {
+            Some(ref suggestion) => write!(formatter, " ({})", suggestion),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The errors that stopped a swarm program from compiling
+#[derive(Debug)]
+pub struct CompileError {
+    /// Every problem found in the program
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Functions for CompileError
+impl CompileError {
+    /// Constructor
+    pub fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        CompileError {
+            diagnostics: diagnostics,
+        }
+    }
+}
+
+/// Allows CompileError to be printed
+impl fmt::Display for CompileError {
+    /// Writes each diagnostic on its own line
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        for (index, diagnostic) in self.diagnostics.iter().enumerate() {
+            if index > 0 {
+                writeln!(formatter)?;
+            }
+            write!(formatter, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+/// Allows CompileError to be used where an error is wanted
+impl Error for CompileError {
+    /// Description of the error
+    fn description(&self) -> &str {
+        "Failed to compile swarm program"
+    }
+    /// Underlying cause of the error
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
@@ -27,9 +27,18 @@ extern crate serde_json;
 extern crate tokio_core;
 extern crate websocket;
 
+pub mod broadcast;
+pub mod config;
+pub mod delta;
 pub mod entity;
 pub mod error;
+pub mod lint;
 pub mod rpc;
+pub mod replay;
 pub mod server;
+pub mod simulation;
+pub mod spatial;
 pub mod swarm_language;
+pub mod tournament;
+pub mod vm;
 pub mod world;
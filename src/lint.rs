@@ -0,0 +1,236 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+use config::Rules;
+use error::Diagnostic;
+use swarm_language::{line_span, SwarmCommand, SwarmProgram};
+use vm::Instruction;
+
+/// Checks a compiled program for code that probably doesn't do what was intended
+/// Returns a warning for each problem found, in the order of the source
+pub fn lint(program: &SwarmProgram, source: &str, rules: &Rules) -> Vec<Diagnostic> {
+    let mut warnings: Vec<Diagnostic> = Vec::new();
+    if program.bytecode.is_empty() {
+        return warnings;
+    }
+    // Builds a warning covering the line an instruction was compiled from
+    let warning = |index: usize, message: String| {
+        let line = program.source_lines.get(index).cloned().unwrap_or(1);
+        let (start, end) = line_span(source, line);
+        Diagnostic::warning(line, start, end, message)
+    };
+
+    // Programs that never move stay where they spawned
+    let moves = program.bytecode.iter().any(|instruction| match *instruction {
+        Instruction::COMMAND(command) => is_movement(command),
+        _ => false,
+    });
+    if !moves {
+        warnings.push(warning(
+            0,
+            "The program never moves, so the swarm stays where it spawned".into(),
+        ));
+    }
+
+    // Commands that run again before their cooldown is over do nothing
+    for (index, instruction) in program.bytecode.iter().enumerate() {
+        match *instruction {
+            Instruction::COMMAND(SwarmCommand::FIRE) => {
+                let repeat = ticks_until(program, rules, index, |command| command == SwarmCommand::FIRE);
+                if let Some((repeat, ticks)) = repeat {
+                    if (ticks as i64) < rules.fire_cooldown {
+                        warnings.push(warning(
+                            repeat,
+                            format!(
+                                "FIRE runs {} ticks after the last FIRE, but the swarm can only fire every {} ticks",
+                                ticks,
+                                rules.fire_cooldown
+                            ),
+                        ));
+                    }
+                }
+            }
+            Instruction::COMMAND(SwarmCommand::FORMATION(_, _)) => {
+                let repeat = ticks_until(program, rules, index, |command| match command {
+                    SwarmCommand::FORMATION(_, _) => true,
+                    _ => false,
+                });
+                if let Some((repeat, ticks)) = repeat {
+                    if (ticks as i64) < rules.formation_cooldown {
+                        warnings.push(warning(
+                            repeat,
+                            format!(
+                                "FORMATION runs {} ticks after the last FORMATION, but formations can only change every {} ticks",
+                                ticks,
+                                rules.formation_cooldown
+                            ),
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Turns that cancel out each loop leave the swarm going in a straight line
+    if let Some(cycle) = main_loop(program) {
+        let mut turn_amt = 0.0;
+        let mut first_turn = None;
+        let mut aims = false;
+        for &index in cycle.iter() {
+            match program.bytecode[index] {
+                Instruction::COMMAND(SwarmCommand::TURN(amt)) => {
+                    turn_amt += amt;
+                    first_turn = first_turn.or(Some(index));
+                }
+                Instruction::COMMAND(SwarmCommand::AIM(_)) => aims = true,
+                _ => {}
+            }
+        }
+        let remainder = (turn_amt % 360.0).abs();
+        if let Some(first_turn) = first_turn {
+            if moves && !aims && (remainder < 0.001 || 360.0 - remainder < 0.001) {
+                warnings.push(warning(
+                    first_turn,
+                    "The TURNs in this loop add up to 0 degrees, so the swarm moves in a straight line"
+                        .into(),
+                ));
+            }
+        }
+    }
+
+    warnings.sort_by_key(|warning| (warning.line, warning.start));
+    warnings
+}
+
+/// Whether a command moves the swarm
+fn is_movement(command: SwarmCommand) -> bool {
+    match command {
+        SwarmCommand::MOVE |
+        SwarmCommand::LEFT |
+        SwarmCommand::RIGHT |
+        SwarmCommand::UP |
+        SwarmCommand::DOWN => true,
+        _ => false,
+    }
+}
+
+/// The instruction that always runs after the given one
+/// None if it depends on a condition or the call stack
+fn successor(program: &SwarmProgram, index: usize) -> Option<usize> {
+    match program.bytecode[index] {
+        Instruction::COMMAND(_) => Some(program.next_index(index)),
+        Instruction::JUMP(target) => Some(target),
+        _ => None,
+    }
+}
+
+/// Follows the program from a command that ends its tick until a command matches
+/// Returns the index of the matching command and the number of ticks until it runs
+/// None if the path depends on a condition or never reaches a match
+fn ticks_until<F>(
+    program: &SwarmProgram,
+    rules: &Rules,
+    start: usize,
+    matches: F,
+) -> Option<(usize, usize)>
+where
+    F: Fn(SwarmCommand) -> bool,
+{
+    let mut index = start;
+    // The next instruction runs in the tick after the start
+    let mut ticks = 1;
+    // Cycles used in the current tick
+    let mut cycles = 0;
+    for _ in 0..program.bytecode.len() {
+        index = successor(program, index)?;
+        let instruction = program.bytecode[index];
+        // Instructions that go over budget wait for the next tick, like in the VM
+        if cycles > 0 && cycles + instruction.cost() > rules.cycle_budget {
+            ticks += 1;
+            cycles = 0;
+        }
+        cycles += instruction.cost();
+        if let Instruction::COMMAND(command) = instruction {
+            if matches(command) {
+                return Some((index, ticks));
+            }
+            if command.ends_tick() {
+                ticks += 1;
+                cycles = 0;
+            }
+        }
+    }
+    None
+}
+
+/// The instructions of the loop the program settles into, in the order they run
+/// None if the loop depends on a condition or the call stack
+fn main_loop(program: &SwarmProgram) -> Option<Vec<usize>> {
+    let mut visited: Vec<usize> = vec![0];
+    loop {
+        let next = successor(program, visited[visited.len() - 1])?;
+        if let Some(position) = visited.iter().position(|&index| index == next) {
+            return Some(visited.split_off(position));
+        }
+        visited.push(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::Severity;
+
+    /// Lints a program, which must compile
+    fn lint_source(source: &str) -> Vec<Diagnostic> {
+        lint(&source.parse().unwrap(), source, &Rules::default())
+    }
+
+    #[test]
+    fn lint_clean() {
+        assert!(lint_source("MOVE\nTURN 10\nIF ENEMY_NEAR 100\nFIRE\nEND").is_empty());
+        // Register commands spill into later ticks
+        let warnings = lint_source("FIRE\nMOVE\nSET R0 0\nloop:\nADD R0 1\nCMP R0 50\nIF LESS\nJUMP loop\nEND");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn lint_warnings() {
+        let warnings = lint_source("NOOP\nTURN 5");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::WARNING);
+        assert_eq!((warnings[0].line, warnings[0].start, warnings[0].end), (1, 0, 4));
+
+        // The second FIRE is too soon, and so is the first one when the loop comes back to it
+        let warnings = lint_source("MOVE\nFIRE\n  FIRE # again\nTURN 10");
+        assert_eq!(warnings.len(), 2);
+        assert_eq!((warnings[0].line, warnings[1].line), (2, 3));
+        assert_eq!((warnings[1].start, warnings[1].end), (2, 6));
+
+        let warnings = lint_source("start:\nFORMATION SPREAD\nMOVE\nJUMP start");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.starts_with("FORMATION"));
+    }
+
+    #[test]
+    fn lint_straight_line() {
+        let warnings = lint_source("FORMATION GATHER\nloop:\nTURN 20\nMOVE\nTURN -20\nMOVE\nJUMP loop");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].line, 3);
+        // Not when the turns add up
+        assert!(lint_source("TURN 20\nMOVE\nTURN -10\nMOVE").is_empty());
+    }
+}
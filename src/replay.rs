@@ -0,0 +1,202 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+extern crate serde_json;
+
+use config::Rules;
+use error::GenericError;
+use swarm_language::SwarmProgram;
+use world::{write_file, World};
+use std::fs::File;
+use std::path::Path;
+
+/// Something a player did to the world
+#[allow(non_camel_case_types)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReplayEvent {
+    /// A player joined with the given ID
+    #[serde(rename = "join")]
+    JOIN(usize),
+    /// A player left
+    #[serde(rename = "leave")]
+    LEAVE(usize),
+    /// A player installed a program, stored as the source they sent
+    #[serde(rename = "program")]
+    PROGRAM(usize, String),
+}
+
+/// Everything needed to play a match again exactly as it happened
+/// Saved to disk as JSON
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    /// The width of the world
+    pub width: f32,
+    /// The height of the world
+    pub height: f32,
+    /// The seed the world's randomness started from
+    pub seed: u64,
+    /// The balance of the game
+    pub rules: Rules,
+    /// Each event with the number of ticks the world had run when it happened
+    pub events: Vec<(u64, ReplayEvent)>,
+}
+
+/// Functions for a replay
+impl Replay {
+    /// Constructor for a replay with no events
+    pub fn new(width: f32, height: f32, seed: u64) -> Self {
+        Replay {
+            width: width,
+            height: height,
+            seed: seed,
+            rules: Rules::default(),
+            events: Vec::new(),
+        }
+    }
+    /// Supplementary function to change the balance of the game
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+    /// Whether recording can carry on into the given world
+    /// The world must have started the same way and have seen every recorded event
+    pub fn continues(&self, world: &World) -> bool {
+        self.width == world.width
+            && self.height == world.height
+            && self.seed == world.seed
+            && self.rules == world.rules
+            && self.events.last().map_or(true, |&(tick, _)| tick <= world.tick)
+    }
+    /// Rebuilds the world as it was after the given number of ticks
+    /// Events that happened after that tick's update are included
+    pub fn play(&self, tick: u64) -> Result<World, GenericError> {
+        let mut world = World::new(self.width, self.height)
+            .with_seed(self.seed)
+            .with_rules(self.rules);
+        for &(event_tick, ref event) in self.events.iter() {
+            if event_tick > tick {
+                break;
+            }
+            while world.tick < event_tick {
+                world.update();
+            }
+            match *event {
+                ReplayEvent::JOIN(id) => world.add_player(id),
+                ReplayEvent::LEAVE(id) => world.remove_player(id),
+                ReplayEvent::PROGRAM(id, ref source) => {
+                    let max_length = self.rules.max_program_length;
+                    let program = SwarmProgram::from_source(source, max_length).map_err(|error| {
+                        GenericError::new(format!("Program at tick {} doesn't compile: {}", event_tick, error))
+                    })?;
+                    world.update_program(id, program);
+                }
+            }
+        }
+        while world.tick < tick {
+            world.update();
+        }
+        Ok(world)
+    }
+    /// Reads a replay from a file
+    pub fn load(path: &Path) -> Result<Self, GenericError> {
+        File::open(path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| serde_json::from_reader(file).map_err(|error| error.to_string()))
+            .map_err(|error| GenericError::new(format!("{}: {}", path.display(), error)))
+    }
+    /// Serializes the replay, to be written once the world is no longer needed
+    pub fn serialize(&self) -> Result<String, GenericError> {
+        serde_json::to_string(self)
+            .map_err(|error| GenericError::new(format!("Failed to serialize the replay: {}", error)))
+    }
+    /// Writes the replay to a file
+    pub fn save(&self, path: &Path) -> Result<(), GenericError> {
+        write_file(path, &self.serialize()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let mut world = World::new(800.0, 600.0).with_seed(11).with_replay();
+        for id in 0..3 {
+            world.add_player(id);
+        }
+        for _ in 0..50 {
+            world.update();
+        }
+        // Comments, blank lines and handlers before the main body move the lines clients are shown
+        world.update_program(1, "# hunt\n\nON WALL\nTURN 30\nEND\nAIM NEAREST\n\nMOVE\nFIRE".parse().unwrap());
+        for _ in 0..50 {
+            world.update();
+        }
+        world.remove_player(2);
+        world.add_player(3);
+        // Events are part of the tick they happened after
+        let halfway = serde_json::to_string(&world.get_state()).unwrap();
+        for _ in 0..70 {
+            world.update();
+        }
+
+        // The replay survives being saved as JSON
+        let replay = world.replay.clone().unwrap();
+        let json = serde_json::to_string(&replay).unwrap();
+        let replay: Replay = serde_json::from_str(&json).unwrap();
+        assert_eq!(replay.events.len(), 6);
+        match replay.events[3] {
+            (50, ReplayEvent::PROGRAM(1, ref source)) => assert!(source.contains("hunt")),
+            ref event => panic!("Unexpected event {:?}", event),
+        }
+
+        let state = |tick| serde_json::to_string(&replay.play(tick).unwrap().get_state()).unwrap();
+        assert_eq!(state(100), halfway);
+        assert_eq!(state(170), serde_json::to_string(&world.get_state()).unwrap());
+    }
+
+    #[test]
+    fn test_replay_rules() {
+        let mut rules = Rules::default();
+        rules.max_program_length = 50;
+        let mut world = World::new(800.0, 600.0).with_seed(4).with_replay().with_rules(rules);
+        world.add_player(0);
+        let source = vec!["MOVE"; 30].join("\n");
+        world.update_program(0, SwarmProgram::from_source(&source, 50).unwrap());
+        // Programs are compiled with the limit the world was played with
+        let replay = world.replay.clone().unwrap();
+        assert_eq!(replay.play(10).unwrap().swarms[&0].program.bytecode.len(), 30);
+    }
+
+    #[test]
+    fn test_replay_continues() {
+        let mut world = World::new(800.0, 600.0).with_seed(5).with_replay();
+        world.add_player(0);
+        world.update();
+        let replay = world.replay.clone().unwrap();
+        // A world restored from a snapshot taken with the replay carries on recording
+        assert!(replay.continues(&world));
+        world.update();
+        assert!(replay.continues(&world));
+        assert!(!replay.continues(&World::new(800.0, 600.0).with_seed(6)));
+        // Events after the snapshot aren't in the restored world
+        world.add_player(1);
+        let replay = world.replay.clone().unwrap();
+        let mut restored = World::new(800.0, 600.0).with_seed(5);
+        restored.update();
+        assert!(!replay.continues(&restored));
+    }
+}
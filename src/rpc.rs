@@ -1,5 +1,7 @@
 extern crate serde_json;
 
+use error::Diagnostic;
+use delta::WorldDelta;
 use world::WorldState;
 
 /// Represents a response sent to the client
@@ -22,6 +24,10 @@ impl Response {
                 message_type: "i".into(),
                 message: ResponseMessage::CONFIG(configuration),
             },
+            ResponseMessage::DELTA(delta) => Response {
+                message_type: "d".into(),
+                message: ResponseMessage::DELTA(delta),
+            },
             ResponseMessage::COMPILE(compile_result) => Response {
                 message_type: "c".into(),
                 message: ResponseMessage::COMPILE(compile_result),
@@ -38,6 +44,9 @@ pub enum ResponseMessage {
     /// Sends the world state
     #[serde(rename = "world")]
     WORLD(WorldState),
+    /// Sends the changes to the world since a state the client has
+    #[serde(rename = "delta")]
+    DELTA(WorldDelta),
     /// Sends configuration
     #[serde(rename = "config")]
     CONFIG(Configuration),
@@ -60,6 +69,8 @@ pub struct CompileResult {
     success: bool,
     /// Error if applicable
     error: String,
+    /// Problems found in the program, with their locations
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl CompileResult {
@@ -68,8 +79,14 @@ impl CompileResult {
         CompileResult {
             success: success,
             error: error,
+            diagnostics: Vec::new(),
         }
     }
+    /// Supplementary function to add diagnostics to a result
+    pub fn with_diagnostics(mut self, diagnostics: Vec<Diagnostic>) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
 }
 
 impl Configuration {
@@ -90,8 +107,80 @@ pub struct Vec2 {
     y: f32,
 }
 
+/// The area of the world a client is showing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    /// Left edge
+    pub min_x: f32,
+    /// Top edge
+    pub min_y: f32,
+    /// Right edge
+    pub max_x: f32,
+    /// Bottom edge
+    pub max_y: f32,
+}
+
+/// Functions for a viewport
+impl Viewport {
+    /// The smallest area containing every point, such as the corners of the screen
+    /// Returns None if there are no points
+    pub fn from_points(points: &[Vec2]) -> Option<Self> {
+        let first = points.first()?;
+        let mut viewport = Viewport {
+            min_x: first.x,
+            min_y: first.y,
+            max_x: first.x,
+            max_y: first.y,
+        };
+        for point in points.iter() {
+            viewport.min_x = viewport.min_x.min(point.x);
+            viewport.min_y = viewport.min_y.min(point.y);
+            viewport.max_x = viewport.max_x.max(point.x);
+            viewport.max_y = viewport.max_y.max(point.y);
+        }
+        Some(viewport)
+    }
+    /// Whether a box overlaps the viewport grown by a margin on every side
+    pub fn overlaps(&self, min_x: f32, min_y: f32, max_x: f32, max_y: f32, margin: f32) -> bool {
+        min_x <= self.max_x + margin && max_x >= self.min_x - margin && min_y <= self.max_y + margin
+            && max_y >= self.min_y - margin
+    }
+}
+
 /// A request for compilation
 #[derive(Deserialize)]
 pub struct CompileRequest {
     pub program: String,
 }
+
+/// Tells the server which world state the client last received
+/// Changes are sent from that state until the next one is acknowledged
+#[derive(Deserialize)]
+pub struct Acknowledgement {
+    /// The tick of the state
+    pub ack: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_viewport() {
+        let points: Vec<Vec2> = serde_json::from_str(r#"[{"x": 300, "y": 50}, {"x": -20, "y": 250}]"#).unwrap();
+        let viewport = Viewport::from_points(&points).unwrap();
+        assert_eq!(
+            viewport,
+            Viewport {
+                min_x: -20.0,
+                min_y: 50.0,
+                max_x: 300.0,
+                max_y: 250.0,
+            }
+        );
+        assert!(viewport.overlaps(290.0, 240.0, 310.0, 260.0, 0.0));
+        assert!(!viewport.overlaps(320.0, 100.0, 330.0, 110.0, 0.0));
+        assert!(viewport.overlaps(320.0, 100.0, 330.0, 110.0, 50.0));
+        assert!(Viewport::from_points(&[]).is_none());
+    }
+}
@@ -15,79 +15,341 @@
 // along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
 extern crate serde_json;
 
+use broadcast::Broadcaster;
+use config::ServerConfig;
+use error::GenericError;
 use futures::{Future, Sink, Stream};
-use rpc::{CompileRequest, CompileResult, Configuration, Response, ResponseMessage, Vec2};
+use futures::sync::{mpsc, oneshot};
+use lint::lint;
+use replay::Replay;
+use rpc::{Acknowledgement, CompileRequest, CompileResult, Configuration, Response, ResponseMessage,
+          Vec2, Viewport};
 use std::fmt::Debug;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
 use std::ops::DerefMut;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
-use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
 use std::thread;
 use std::time::Duration;
 use swarm_language::SwarmProgram;
+use tokio_core::net::TcpListener;
 use tokio_core::reactor::{Core, Handle};
 use websocket::message::{Message, OwnedMessage};
-use websocket::async::Server;
-use world::World;
+use websocket::async::server::IntoWs;
+use world::{write_file, World, WorldState};
 
 /// Represents a server for the game
-// TODO: populate this with parameters
+/// Each server has its own world, so several can run in one process
 pub struct GameServer {
-    // Hostname for the websocket to listen on
-    //hostname: String,
-    // Port for the websocket to listen on
-    //port: u16,
-    // Number of server updates per second
-    //update_freq: u64,
-    // The game's world
-    //world: Arc<RwLock<World>>,
-    // A counter used to assign player IDs to session
-    //id_counter: AtomicUsize,
+    /// The server's settings
+    config: ServerConfig,
 }
 impl GameServer {
     /// Constructor
-    /// hostname: address for the websocket to listen on
-    /// port: port for the websocket to listen on
-    /// width: width of the game world
-    /// height: height of the game world
-    /// update_freq: server updates per second
-    /*pub fn new(hostname: &str, port: u16, width: f32, height: f32, update_freq: u64) -> Self {
-        GameServer {
-            hostname: hostname.into(),
-            port: port,
-            update_freq: update_freq,
-            world: Arc::new(RwLock::new(World::new(width, height))),
-            id_counter: AtomicUsize::new(0),
+    pub fn new(config: ServerConfig) -> Self {
+        GameServer { config: config }
+    }
+    /// Starts the server on its own thread
+    /// Fails without starting anything if the address can't be bound
+    pub fn start(&self) -> Result<ServerHandle, GenericError> {
+        let hostname = self.config.hostname.clone();
+        let port: u16 = self.config.port;
+        // Bind to an address before starting anything that would outlive a failure
+        let listener = StdTcpListener::bind((hostname.as_str(), port)).map_err(|error| {
+            GenericError::new(format!("Failed to bind to {}:{}: {}", hostname, port, error))
+        })?;
+        // Port 0 lets the system pick one, so the address is read back from the socket
+        let address = listener.local_addr().map_err(|error| {
+            GenericError::new(format!("Failed to read the address of {}:{}: {}", hostname, port, error))
+        })?;
+        let (stop, stopped) = oneshot::channel();
+        let config = self.config.clone();
+        let thread = thread::spawn(move || GameServer::run(config, listener, address, stopped));
+        Ok(ServerHandle {
+            address: address,
+            stop: stop,
+            thread: thread,
+        })
+    }
+    /// Serves clients until the server fails or is told to stop
+    fn run(
+        config: ServerConfig,
+        listener: StdTcpListener,
+        address: SocketAddr,
+        stopped: oneshot::Receiver<()>,
+    ) -> Result<(), GenericError> {
+        // Server parameters
+        let update_freq: u64 = config.update_freq;
+        let broadcast_interval: u64 = config.broadcast_interval;
+        let keyframe_interval: u64 = config.keyframe_interval;
+        let max_players: usize = config.max_players;
+        // Where the world is saved, so it survives restarts
+        let snapshot_path: Option<PathBuf> = config.snapshot_path.clone();
+        // Where the replay of the world is saved, if one is recorded
+        let replay_path: Option<PathBuf> = config.replay_path.clone();
+        // Ticks between snapshots
+        let snapshot_interval: u64 = 60 * update_freq;
+        let world = load_world(&config);
+        // New players get IDs above those from before a restart, so no ID is reused
+        // Connections don't survive a restart, so the saved swarms carry on running the programs they had
+        let first_id: usize = world.swarms.keys().next_back().map_or(0, |id| id + 1);
+        let world: Arc<RwLock<World>> = Arc::new(RwLock::new(world));
+        // Copy a reference to world for the clients to use
+        let world_client = world.clone();
+        // Copy a reference to world to save it once the server stops
+        let world_final = world.clone();
+        let final_snapshot_path = snapshot_path.clone();
+        let final_replay_path = replay_path.clone();
+        // Pushes the world state to every client
+        let broadcaster: Arc<Broadcaster> = Arc::new(Broadcaster::new(keyframe_interval));
+        let broadcaster_client = broadcaster.clone();
+        // Used for serving
+        let mut core = Core::new()
+            .map_err(|error| GenericError::new(format!("Failed to initialize core: {}", error)))?;
+        let handle = core.handle();
+        let listener = TcpListener::from_listener(listener, &address, &handle)
+            .map_err(|error| GenericError::new(format!("Failed to listen on {}: {}", address, error)))?;
+        // Whether the server is still running, so the world's thread can stop with it
+        let running: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+        let running_world = running.clone();
+        // Start the world's main thread
+        let updater = thread::spawn(move || {
+            // TODO: nanoseconds accuracy for this
+            let update_delta = Duration::from_micros(1000000 / update_freq);
+            // Get reference to the world
+            let world = world.clone();
+            // Elapsed time of last update
+            let mut last_update_time = Duration::from_millis(0);
+            // Main loop
+            while running_world.load(AtomicOrdering::SeqCst) {
+                // Log time elapsed in previous update
+                debug!(
+                    "Last update took {}s, {}ns",
+                    last_update_time.as_secs(),
+                    last_update_time.subsec_nanos()
+                );
+                // Sleep for some amount of time
+                if last_update_time <= update_delta {
+                    thread::sleep(update_delta - last_update_time);
+                } else {
+                    // If the thread update took too long
+                    warn!("Update thread took too long!")
+                }
+                // Whether to save the world once it is updated
+                let mut save = false;
+                // Lock the world for writing
+                match world.write() {
+                    Ok(mut write_lock) => {
+                        // Get a mutable reference to the world
+                        let world_ref = write_lock.deref_mut();
+                        // Update the world
+                        last_update_time = world_ref.update();
+                        // Save the world every so often
+                        save = world_ref.tick % snapshot_interval == 0;
+                        // Write lock goes out of scope, world is again available to be read
+                    }
+                    Err(error) => error!("Error retrieving write lock in update thread: {}", error),
+                }
+                if save {
+                    save_world(&world, &snapshot_path, &replay_path);
+                }
+                // Push the world state to the clients every so often
+                match world.read() {
+                    Ok(world) => if world.tick % broadcast_interval == 0 && !broadcaster.is_empty() {
+                        // Clients without a viewport see the whole world, which is only built once
+                        let mut full_state: Option<Arc<WorldState>> = None;
+                        broadcaster.broadcast_world(|id, viewport| match viewport {
+                            Some(viewport) => Arc::new(world.get_visible_state(id, viewport)),
+                            None => full_state
+                                .get_or_insert_with(|| Arc::new(world.get_state()))
+                                .clone(),
+                        });
+                    },
+                    Err(error) => error!("Error retrieving read lock in update thread: {}", error),
+                }
+            }
+        });
+        // Used to assign IDs to connections (players)
+        let id_counter: AtomicUsize = AtomicUsize::new(first_id);
+        // Number of players connected
+        let num_players: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        // This future represents what this server is going to do.
+        // Handles a stream of incoming connections
+        let server_future = listener.incoming()
+            // Handle errors
+            .then(|result| -> Result<_, ()> { Ok(result.ok()) })
+            .filter_map(|x| x)
+            // Read the websocket handshake of each connection
+            .and_then(|(stream, addr)| {
+                stream.into_ws().then(move |result| -> Result<_, ()> {
+                    Ok(result.ok().map(|upgrade| (upgrade, addr)))
+                })
+            })
+            .filter_map(|x| x)
+            // Handle connections
+            .for_each(move |(upgrade, addr)| {
+                // Log the connection
+                info!("Got a connection from: {}", addr);
+                // Verify protocol
+                if !upgrade.protocols().iter().any(|protocol| protocol == "heroesoftheswarm") {
+                    // Reject connecitons that don't have the supported protocol
+                    spawn_future(upgrade.reject(), "Upgrade Rejection", &handle);
+                    return Ok(());
+                }
+                // Reject players once the server is full
+                if num_players.fetch_add(1, AtomicOrdering::SeqCst) >= max_players {
+                    num_players.fetch_sub(1, AtomicOrdering::SeqCst);
+                    info!("Server is full, rejecting {}", addr);
+                    spawn_future(upgrade.reject(), "Server Full", &handle);
+                    return Ok(());
+                }
+                let players = num_players.clone();
+                let client_handle = handle.clone();
+                let broadcaster = broadcaster_client.clone();
+                // Get a reference to the world for this connection
+                let world = world_client.clone();
+                let w = world.clone();
+                let b = broadcaster.clone();
+                // Get an ID for this connection
+                let session_id: usize = id_counter.fetch_add(1, AtomicOrdering::SeqCst);
+                // Create a swarm for this session
+                match world.write() {
+                    Ok(mut write_lock) => {
+                        // Get a mutable reference to the world
+                        let world_ref = write_lock.deref_mut();
+                        world_ref.add_player(session_id);
+                        // Write lock goes out of scope, world is again available to be read
+                    },
+                    Err(error) => {
+                        error!("Error getting write lock: {}. Player not added", error);
+                        players.fetch_sub(1, AtomicOrdering::SeqCst);
+                        spawn_future(upgrade.reject(), "Failed to add player to world", &handle);
+                        return Ok(());
+                    }
+                }
+                // Start pushing the world to this session
+                let frames = broadcaster.subscribe(session_id);
+                // accept the request to be a ws connection if it does
+                let message_handler = upgrade
+                    // Use our protocol
+                    .use_protocol("heroesoftheswarm")
+                    // Accept the message
+                    .accept()
+                    // Respond so the client knows the connection succeeded 
+                    .and_then(move |(socket, _)| {
+                        //socket.send(Message::text(session_id.to_string()).into());
+                        // Create a config object and send it to the client
+                        let config = Configuration::new(session_id); 
+                        // Create a response
+                        let response = Response::new(ResponseMessage::CONFIG(config));
+                        match response.serialize() {
+                            Ok(serialized) => socket.send(Message::text(serialized).into()),
+                            Err(error) => {
+                                error!("Failed to serialize config");
+                                socket.send(Message::text(r#"{"mt": "error", "message": {"error": "Failed to serialize config"}}"#).into())
+                            }
+                        }
+                    })
+                    // Build a message responder
+                    .and_then(move |socket| {
+                        // Get sink and stream
+                        let (sink, stream) = socket.split();
+                        // Replies to this client's requests
+                        let (replies, reply_stream) = mpsc::unbounded();
+                        // Send world states as they are pushed, along with the replies
+                        // Ends once the client is removed and has no replies left
+                        let sender = frames
+                            .map(OwnedMessage::Text)
+                            .select(reply_stream)
+                            .forward(sink.sink_map_err(move |error| {
+                                info!("Failed to send to Client {}: {:?}", session_id, error)
+                            }))
+                            // Send the close message
+                            .and_then(|(_, sink)| sink.send(OwnedMessage::Close(None)));
+                        spawn_future(sender, "Client Sender", &client_handle);
+                        stream
+                            // For all messages until the connection closes
+                            .take_while(move |message| Ok(!message.is_close()))
+                            // Handle the input and queue the output
+                            .for_each(move |message| {
+                                // Log the message
+                                debug!("Message from Client {}: {:?}", session_id, message);
+                                // Handle the message by type
+                                if let Some(reply) = GameServer::handle_message(message, session_id, &world, &b) {
+                                    // Only fails once the sender has stopped, when the client is gone anyway
+                                    let _ = replies.unbounded_send(reply);
+                                }
+                                Ok(())
+                            })
+                    })
+                    // Clean up however the connection ended
+                    .then(move |result| {
+                        // Stop pushing the world to this session
+                        broadcaster.unsubscribe(session_id);
+                        // Delete the swarm from this session
+                        match w.write() {
+                            Ok(mut write_lock) => {
+                                // Get a mutable reference to the world
+                                let world_ref = write_lock.deref_mut();
+                                // Remove the player
+                                world_ref.remove_player(session_id);
+                                // Write lock goes out of scope, world is again available to be read
+                            },
+                            Err(error) => {
+                                error!("Error getting write lock: {}. Player not removed", error);
+                            }
+                        };
+                        players.fetch_sub(1, AtomicOrdering::SeqCst);
+                        result
+                    });
+
+                spawn_future(message_handler, "Client Status", &handle);
+            
+                Ok(())
+            });
+        info!("Starting the server at {}", address);
+        // Serve until the handle says to stop, or is dropped
+        let stopped = stopped.then(|_| -> Result<(), ()> { Ok(()) });
+        let result = core.run(server_future.select(stopped).map(|_| ()).map_err(|_| ()));
+        // Stop updating the world once nobody can connect to it
+        running.store(false, AtomicOrdering::SeqCst);
+        if updater.join().is_err() {
+            error!("The update thread panicked");
         }
-    }*/
-    /// Starts the server
-    pub fn start() {}
+        save_world(&world_final, &final_snapshot_path, &final_replay_path);
+        result.map_err(|_| GenericError::new("The server stopped accepting connections".into()))
+    }
     /// Handles an incoming websocket message
     fn handle_message(
         message: OwnedMessage,
         player_id: usize,
         world: &Arc<RwLock<World>>,
+        broadcaster: &Broadcaster,
     ) -> Option<OwnedMessage> {
         match message {
             // Handle incoming text data
             OwnedMessage::Text(data) => {
-                // Try to parse it as a request for updates
+                // Try to parse it as a viewport
+                // The world state is pushed every tick, so there's nothing to send back
                 match serde_json::from_str::<Vec<Vec2>>(&data) {
-                    Ok(coords) => match world.read() {
-                        Ok(world) => {
-                            // Create a message type
-                            let message = Response::new(ResponseMessage::WORLD(world.get_state()));
-                            match message.serialize() {
-                                Ok(message) => return Some(OwnedMessage::Text(message)),
-                                Err(error) => {}
-                            }
+                    Ok(points) => {
+                        match Viewport::from_points(&points) {
+                            Some(viewport) => broadcaster.set_viewport(player_id, viewport),
+                            None => debug!("Ignoring an empty viewport from Client {}", player_id),
                         }
-                        Err(error) => {
-                            warn!("Failed to get read lock on world. Not sending world state");
-                            return None;
-                        }
-                    },
+                        return None;
+                    }
                     Err(_) => debug!("Failed to parse request as a viewport"),
                 };
+                // Try to parse it as an acknowledgement of a world state
+                match serde_json::from_str::<Acknowledgement>(&data) {
+                    Ok(acknowledgement) => {
+                        broadcaster.acknowledge(player_id, acknowledgement.ack);
+                        return None;
+                    }
+                    Err(_) => debug!("Failed to parse request as an acknowledgement"),
+                };
                 // Try to parse it as a request for compilation
                 match serde_json::from_str::<CompileRequest>(&data) {
                     Ok(compile_request) => match world.write() {
@@ -95,13 +357,17 @@ impl GameServer {
                             let world_ref = write_lock.deref_mut();
                             info!("Compile request: {}", data);
                             // Create a message type
-                            match compile_request.program.parse::<SwarmProgram>() {
+                            let max_length = world_ref.rules.max_program_length;
+                            match SwarmProgram::from_source(&compile_request.program, max_length) {
                                 Ok(compiled_program) => {
+                                    // Warnings don't stop the program from running
+                                    let warnings =
+                                        lint(&compiled_program, &compile_request.program, &world_ref.rules);
                                     // Update the program
                                     world_ref.update_program(player_id, compiled_program);
                                     // Respond with success
                                     let message = Response::new(ResponseMessage::COMPILE(
-                                        CompileResult::new(true, "".into()),
+                                        CompileResult::new(true, "".into()).with_diagnostics(warnings),
                                     ));
                                     match message.serialize() {
                                         Ok(message) => return Some(OwnedMessage::Text(message)),
@@ -112,7 +378,8 @@ impl GameServer {
                                     info!("Failed to compile program: {}", error);
                                     // Generate an output message
                                     let message = Response::new(ResponseMessage::COMPILE(
-                                        CompileResult::new(false, error.to_string()),
+                                        CompileResult::new(false, error.to_string())
+                                            .with_diagnostics(error.diagnostics),
                                     ));
                                     match message.serialize() {
                                         Ok(message) => return Some(OwnedMessage::Text(message)),
@@ -126,7 +393,7 @@ impl GameServer {
                             return None;
                         }
                     },
-                    Err(_) => debug!("Failed to parse request as a viewport"),
+                    Err(_) => debug!("Failed to parse request as a compile request"),
                 };
                 // If it matches none of the cases, just return None
                 None
@@ -143,161 +410,107 @@ impl GameServer {
     }
 }
 
-/// Runs the server
-// TODO: Move all of this into impl for GameServer
-pub fn run() {
-    // Server parameters
-    let hostname = "0.0.0.0";
-    let port: u16 = 8080;
-    let update_freq: u64 = 60;
-    // Create the world
-    let world: Arc<RwLock<World>> = Arc::new(RwLock::new(World::new(1600.0, 900.0)));
-    // Copy a reference to world for the clients to use
-    let world_client = world.clone();
-    // Start the world's main thread
-    thread::spawn(move || {
-        // TODO: nanoseconds accuracy for this
-        let update_delta = Duration::from_micros(1000000 / update_freq);
-        // Get reference to the world
-        let world = world.clone();
-        // Elapsed time of last update
-        let mut last_update_time = Duration::from_millis(0);
-        // Main loop
-        loop {
-            // Log time elapsed in previous update
-            debug!(
-                "Last update took {}s, {}ns",
-                last_update_time.as_secs(),
-                last_update_time.subsec_nanos()
-            );
-            // Sleep for some amount of time
-            if last_update_time <= update_delta {
-                thread::sleep(update_delta - last_update_time);
-            } else {
-                // If the thread update took too long
-                warn!("Update thread took too long!")
-            }
-            // Lock the world for writing
-            match world.write() {
-                Ok(mut write_lock) => {
-                    // Get a mutable reference to the world
-                    let world_ref = write_lock.deref_mut();
-                    // Update the world
-                    last_update_time = world_ref.update();
-                    // Write lock goes out of scope, world is again available to be read
-                }
-                Err(error) => error!("Error retrieving write lock in update thread: {}", error),
+/// A running server
+/// The server stops once this is shut down or dropped
+pub struct ServerHandle {
+    /// The address the server is listening on
+    address: SocketAddr,
+    /// Tells the server to stop
+    stop: oneshot::Sender<()>,
+    /// The thread serving clients, which returns how the server stopped
+    thread: thread::JoinHandle<Result<(), GenericError>>,
+}
+
+/// Functions for a server handle
+impl ServerHandle {
+    /// The address the server is listening on
+    /// Has the port the system picked if the settings asked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+    /// Stops the server, returning once the world is saved
+    pub fn shutdown(self) -> Result<(), GenericError> {
+        // Only fails if the server already stopped, which join reports
+        let _ = self.stop.send(());
+        join(self.thread)
+    }
+    /// Waits for the server to stop, which it only does if it fails
+    pub fn wait(self) -> Result<(), GenericError> {
+        let ServerHandle { stop, thread, .. } = self;
+        let result = join(thread);
+        // Dropping this tells the server to stop, so it is kept until the server has
+        drop(stop);
+        result
+    }
+}
+
+/// Waits for a server's thread to finish, returning how the server stopped
+fn join(thread: thread::JoinHandle<Result<(), GenericError>>) -> Result<(), GenericError> {
+    thread
+        .join()
+        .unwrap_or_else(|_| Err(GenericError::new("The server thread panicked".into())))
+}
+
+/// Creates the world for a server from its settings
+/// Resumes the saved world and its replay if there are any
+fn load_world(config: &ServerConfig) -> World {
+    // Resume the saved world if there is one, otherwise create a new one
+    let saved_world = match config.snapshot_path {
+        Some(ref path) if path.exists() => match World::load(path) {
+            Ok(world) => {
+                info!("Resuming the world saved at {}", path.display());
+                Some(world)
             }
-        }
-    });
-    // Used to assign IDs to connections (players)
-    let id_counter: AtomicUsize = AtomicUsize::new(0);
-    // Used for serving
-    let mut core = Core::new().expect("Failed to initialize core");
-    let handle = core.handle();
-    // Bind to an address
-    let server = Server::bind(format!("{}:{}", hostname, port), &handle)
-        .expect("Failed to bind to an address");
-    // This future represents what this server is going to do.
-    // Handles a stream of incoming connections
-    let server_future = server.incoming()
-        // Handle errors
-        .map(Some)
-        .or_else(|_| -> Result<_, ()> { Ok(None) })
-        .filter_map(|x| x) 
-        //.map_err(move |InvalidConnection { error, .. }| error)
-        // Handle connections
-        .for_each(move |(upgrade, addr)| {
-            // Log the connection
-            info!("Got a connection from: {}", addr);
-            // Verify protocol
-            if !upgrade.protocols().iter().any(|protocol| protocol == "heroesoftheswarm") {
-                // Reject connecitons that don't have the supported protocol
-                spawn_future(upgrade.reject(), "Upgrade Rejection", &handle);
-                return Ok(());
+            Err(error) => {
+                error!("Failed to resume the saved world: {}", error);
+                None
             }
-            // Get a reference to the world for this connection
-            let world = world_client.clone();
-            let w = world.clone();
-            // Get an ID for this connection
-            let session_id: usize = id_counter.fetch_add(1, AtomicOrdering::SeqCst);
-            // Create a swarm for this session
-            match world.write() {
-                Ok(mut write_lock) => {
-                    // Get a mutable reference to the world
-                    let world_ref = write_lock.deref_mut();
-                    world_ref.add_player(session_id);
-                    // Write lock goes out of scope, world is again available to be read
-                },
-                Err(error) => {
-                    error!("Error getting write lock: {}. Player not added", error);
-                    spawn_future(upgrade.reject(), "Failed to add player to world", &handle);
-                    return Ok(());
-                }
+        },
+        _ => None,
+    };
+    let resumed = saved_world.is_some();
+    let mut world = saved_world
+        .unwrap_or_else(|| World::new(config.width, config.height))
+        .with_rules(config.rules);
+    // Record everything players do, carrying on from the saved replay after a restart
+    if let Some(ref path) = config.replay_path {
+        if !resumed {
+            world = world.with_replay();
+        } else {
+            match Replay::load(path) {
+                Ok(ref replay) if replay.continues(&world) => world.replay = Some(replay.clone()),
+                Ok(_) => warn!("The replay at {} is from another world. Not recording", path.display()),
+                Err(error) => warn!("Failed to resume the replay: {}. Not recording", error),
             }
-            // accept the request to be a ws connection if it does
-            let message_handler = upgrade
-                // Use our protocol
-                .use_protocol("heroesoftheswarm")
-                // Accept the message
-                .accept()
-                // Respond so the client knows the connection succeeded 
-                .and_then(move |(socket, _)| {
-                    //socket.send(Message::text(session_id.to_string()).into());
-                    // Create a config object and send it to the client
-                    let config = Configuration::new(session_id); 
-                    // Create a response
-                    let response = Response::new(ResponseMessage::CONFIG(config));
-                    match response.serialize() {
-                        Ok(serialized) => socket.send(Message::text(serialized).into()),
-                        Err(error) => {
-                            error!("Failed to serialize config");
-                            socket.send(Message::text(r#"{"mt": "error", "message": {"error": "Failed to serialize config"}}"#).into())
-                        }
-                    }
-                })
-                // Build a message responder
-                .and_then(move |socket| {
-                    // Get sink and stream
-                    let (sink, stream) = socket.split();
-                    stream
-                        // For all messages until the connection closes
-                        .take_while(move |message| Ok(!message.is_close()))
-                        // Handle the input and generate output
-                        .filter_map(move |message| {
-                            // Log the message
-                            debug!("Message from Client {}: {:?}", session_id, message);
-                            // Handle the message by type
-                            GameServer::handle_message(message, session_id, &world)
-                        })
-                        .forward(sink)
-                        .and_then(move |(_, sink)| {
-
-                            // Delete the swarm from this session
-                            match w.write() {
-                                Ok(mut write_lock) => {
-                                    // Get a mutable reference to the world
-                                    let world_ref = write_lock.deref_mut();
-                                    // Remove the player
-                                    world_ref.remove_player(session_id);
-                                    // Write lock goes out of scope, world is again available to be read
-                                },
-                                Err(error) => {
-                                    error!("Error getting write lock: {}. Player not removed", error);
-                                
-                                }
-                            };
-                            // Send the close message
-                            sink.send(OwnedMessage::Close(None))
-                        })
-                });
+        }
+    }
+    world
+}
 
-            spawn_future(message_handler, "Client Status", &handle);
-            
-            Ok(())
-        });
-    info!("Starting the server at {}:{}", hostname, port);
-    core.run(server_future).expect("Failed to start server");
+/// Saves the world and its replay to the given files, if any
+/// Both are serialized at the same tick, so they can be resumed together
+/// The files are written once the lock is released, so the world doesn't wait on the disk
+fn save_world(world: &RwLock<World>, snapshot_path: &Option<PathBuf>, replay_path: &Option<PathBuf>) {
+    let (snapshot, replay) = match world.read() {
+        Ok(world) => (
+            snapshot_path.as_ref().map(|_| world.snapshot()),
+            replay_path.as_ref().and(world.replay.as_ref()).map(|replay| replay.serialize()),
+        ),
+        Err(error) => {
+            error!("Error retrieving read lock to save the world: {}", error);
+            return;
+        }
+    };
+    if let (&Some(ref path), Some(snapshot)) = (snapshot_path, snapshot) {
+        if let Err(error) = snapshot.and_then(|snapshot| write_file(path, &snapshot)) {
+            error!("Failed to save the world: {}", error);
+        }
+    }
+    if let (&Some(ref path), Some(replay)) = (replay_path, replay) {
+        if let Err(error) = replay.and_then(|replay| write_file(path, &replay)) {
+            error!("Failed to save the replay: {}", error);
+        }
+    }
 }
 
 // TODO: learn what this does and how it works
@@ -311,3 +524,32 @@ where
             .map(move |_| info!("{}: Finished.", desc)),
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_load_world() {
+        let path = env::temp_dir().join("heroesoftheswarm_test_load_world.json");
+        let mut world = World::new(800.0, 600.0).with_seed(8);
+        world.add_player(3);
+        world.add_player(5);
+        world.update_program(3, "FIRE".parse().unwrap());
+        for _ in 0..5 {
+            world.update();
+        }
+        world.save(&path).unwrap();
+        let mut config = ServerConfig::default();
+        config.snapshot_path = Some(path.clone());
+        let restored = load_world(&config);
+        fs::remove_file(&path).unwrap();
+        // The saved swarms and their bullets are still in the world
+        assert_eq!(restored.swarms.keys().cloned().collect::<Vec<usize>>(), vec![3, 5]);
+        assert_eq!(restored.bullets.len(), world.bullets.len());
+        assert!(!restored.bullets.is_empty());
+        assert_eq!(restored.tick, 5);
+    }
+}
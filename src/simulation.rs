@@ -0,0 +1,191 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+use config::Rules;
+use error::GenericError;
+use swarm_language::SwarmProgram;
+use world::World;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+/// Settings for a match played without a server
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    /// The width of the world
+    pub width: f32,
+    /// The height of the world
+    pub height: f32,
+    /// Number of ticks to play
+    pub ticks: u64,
+    /// Seed for the world's randomness
+    pub seed: u64,
+    /// The balance of the game
+    pub rules: Rules,
+}
+
+/// Functions for a simulation
+impl Simulation {
+    /// Constructor
+    pub fn new(width: f32, height: f32, ticks: u64, seed: u64) -> Self {
+        Simulation {
+            width: width,
+            height: height,
+            ticks: ticks,
+            seed: seed,
+            rules: Rules::default(),
+        }
+    }
+    /// Supplementary function to change the balance of the game
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+    /// Plays a match between named programs until the tick limit
+    /// Each program controls one swarm, with the program's index as its player ID
+    pub fn run(&self, programs: &[(String, SwarmProgram)]) -> SimulationResult {
+        let mut world = World::new(self.width, self.height)
+            .with_seed(self.seed)
+            .with_rules(self.rules);
+        for (id, &(_, ref program)) in programs.iter().enumerate() {
+            world.add_player(id);
+            world.update_program(id, program.clone());
+        }
+        for _ in 0..self.ticks {
+            world.update();
+        }
+        // Rank the programs by experience, then by how long they survived
+        let mut leaderboard: Vec<ProgramResult> = world
+            .swarms
+            .iter()
+            .map(|(id, swarm)| ProgramResult {
+                player: *id,
+                name: programs[*id].0.clone(),
+                experience: swarm.experience,
+                members: swarm.members.len(),
+                kills: swarm.stats.kills,
+                damage_dealt: swarm.stats.damage_dealt,
+                damage_taken: swarm.stats.damage_taken,
+                survival_time: swarm.stats.eliminated_at.unwrap_or(world.tick),
+            })
+            .collect();
+        leaderboard.sort_by(|a, b| {
+            (b.experience, b.survival_time).cmp(&(a.experience, a.survival_time))
+        });
+        SimulationResult {
+            seed: self.seed,
+            ticks: world.tick,
+            leaderboard: leaderboard,
+        }
+    }
+}
+
+/// The outcome of a simulated match
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SimulationResult {
+    /// Seed the match was played with
+    pub seed: u64,
+    /// Number of ticks played
+    pub ticks: u64,
+    /// How each program did, from 1st place to last
+    pub leaderboard: Vec<ProgramResult>,
+}
+
+/// How one program did in a simulated match
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ProgramResult {
+    /// Player ID the program played as, which is its index in the match
+    pub player: usize,
+    /// Name of the program
+    pub name: String,
+    /// Experience gained by the program's swarm
+    pub experience: i64,
+    /// Members left at the end of the match
+    pub members: usize,
+    /// Enemy members destroyed
+    pub kills: usize,
+    /// Damage dealt to other swarms
+    pub damage_dealt: i64,
+    /// Damage taken from other swarms
+    pub damage_taken: i64,
+    /// Ticks the swarm had members for
+    pub survival_time: u64,
+}
+
+/// Reads and compiles a swarm program from a file
+/// The program is named after the file, without its extension
+/// max_length: the most instructions the program can compile to
+pub fn load_program(
+    path: &Path,
+    max_length: usize,
+) -> Result<(String, SwarmProgram), GenericError> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|error| GenericError::new(format!("{}: {}", path.display(), error)))?;
+    let program = SwarmProgram::from_source(&source, max_length)
+        .map_err(|error| GenericError::new(format!("{}: {}", path.display(), error)))?;
+    let name = path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string());
+    Ok((name, program))
+}
+
+/// Reads and compiles every .swarm file in a directory
+/// Programs are sorted by name
+/// max_length: the most instructions each program can compile to
+pub fn load_directory(
+    directory: &Path,
+    max_length: usize,
+) -> Result<Vec<(String, SwarmProgram)>, GenericError> {
+    let entries = fs::read_dir(directory)
+        .map_err(|error| GenericError::new(format!("{}: {}", directory.display(), error)))?;
+    let mut programs: Vec<(String, SwarmProgram)> = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|error| GenericError::new(format!("{}: {}", directory.display(), error)))?
+            .path();
+        if path.extension().map_or(false, |extension| extension == "swarm") {
+            programs.push(load_program(&path, max_length)?);
+        }
+    }
+    programs.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(programs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulation() {
+        let programs = vec![
+            ("hunter".to_string(), "AIM NEAREST\nMOVE\nFIRE".parse().unwrap()),
+            ("sitter".to_string(), "NOOP".parse().unwrap()),
+        ];
+        let simulation = Simulation::new(400.0, 400.0, 600, 7);
+        let result = simulation.run(&programs);
+        assert_eq!(result.ticks, 600);
+        assert_eq!(result.leaderboard.len(), 2);
+        let hunter = &result.leaderboard[0];
+        let sitter = &result.leaderboard[1];
+        assert_eq!((hunter.name.as_str(), sitter.name.as_str()), ("hunter", "sitter"));
+        assert!(hunter.damage_dealt > 0);
+        assert_eq!(hunter.damage_dealt, sitter.damage_taken);
+        assert_eq!(hunter.kills, 10 - sitter.members);
+        // The same seed plays the same match
+        assert_eq!(simulation.run(&programs), result);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+
+/// A uniform grid over the world that finds the boxes a point could be in
+/// Rebuilt every tick, since everything in the world moves
+#[derive(Clone, Debug)]
+pub struct SpatialGrid {
+    /// Width and height of each cell
+    cell_size: f32,
+    /// Number of cells across the world
+    columns: usize,
+    /// Number of cells down the world
+    rows: usize,
+    /// IDs of the boxes overlapping each cell, row by row
+    /// Each cell's IDs are in the order they were inserted
+    cells: Vec<Vec<usize>>,
+}
+
+/// Functions for the spatial grid
+impl SpatialGrid {
+    /// Default width and height of each cell
+    /// About the size of a swarm in a formation
+    pub const CELL_SIZE: f32 = 100.0;
+    /// Constructor
+    /// width: the width of the world
+    /// height: the height of the world
+    /// cell_size: the width and height of each cell
+    pub fn new(width: f32, height: f32, cell_size: f32) -> Self {
+        let columns = ((width / cell_size).ceil() as usize).max(1);
+        let rows = ((height / cell_size).ceil() as usize).max(1);
+        SpatialGrid {
+            cell_size: cell_size,
+            columns: columns,
+            rows: rows,
+            cells: vec![Vec::new(); columns * rows],
+        }
+    }
+    /// The column and row of the cell containing a point
+    /// Points outside the world are put in the nearest cell on its edge
+    fn cell(&self, x: f32, y: f32) -> (usize, usize) {
+        let column = (x / self.cell_size).floor().max(0.0) as usize;
+        let row = (y / self.cell_size).floor().max(0.0) as usize;
+        (column.min(self.columns - 1), row.min(self.rows - 1))
+    }
+    /// Adds a box to every cell it overlaps
+    pub fn insert(&mut self, id: usize, min_x: f32, min_y: f32, max_x: f32, max_y: f32) {
+        let (first_column, first_row) = self.cell(min_x, min_y);
+        let (last_column, last_row) = self.cell(max_x, max_y);
+        for row in first_row..(last_row + 1) {
+            for column in first_column..(last_column + 1) {
+                self.cells[row * self.columns + column].push(id);
+            }
+        }
+    }
+    /// IDs of the boxes that could contain a point, in the order they were inserted
+    /// The boxes still need to be checked, since they only overlap the point's cell
+    pub fn query(&self, x: f32, y: f32) -> &[usize] {
+        let (column, row) = self.cell(x, y);
+        &self.cells[row * self.columns + column]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid() {
+        let mut grid = SpatialGrid::new(1000.0, 500.0, 100.0);
+        grid.insert(3, 50.0, 50.0, 150.0, 80.0);
+        grid.insert(7, 120.0, 20.0, 180.0, 220.0);
+        assert_eq!(grid.query(60.0, 60.0), &[3]);
+        assert_eq!(grid.query(110.0, 90.0), &[3, 7]);
+        assert_eq!(grid.query(150.0, 210.0), &[7]);
+        assert!(grid.query(500.0, 250.0).is_empty());
+        // Boxes and points past the edges use the edge cells
+        grid.insert(9, 950.0, 450.0, 1100.0, 600.0);
+        assert_eq!(grid.query(2000.0, 2000.0), &[9]);
+        assert_eq!(grid.query(-10.0, -10.0), &[3]);
+    }
+}
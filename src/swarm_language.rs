@@ -13,16 +13,50 @@
 //
 // You should have received a copy of the GNU General Public License
 // along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
-use error::GenericError;
+use error::{CompileError, Diagnostic, GenericError};
+use vm::{self, Instruction};
+use std::cmp::Ordering;
+use std::fmt;
 use std::str::FromStr;
 use std::f32;
+use std::i64;
 
-/// The maximum number of commands that can exist in a swarm program
-const MAX_NUM_COMMANDS: usize = 20;
+/// The default maximum number of instructions in a compiled swarm program
+pub const MAX_NUM_COMMANDS: usize = 20;
+/// The number of registers of each type available to a swarm program
+pub const NUM_REGISTERS: usize = 4;
+/// The most a swarm can turn in one command, in degrees
+pub const MAX_TURN: f32 = 30.0;
+/// The most procedure calls that can be in progress at once
+pub const MAX_CALL_DEPTH: usize = 8;
+/// The most space that can be asked for between members of a formation
+pub const MAX_SPACING: f32 = 100.0;
+/// Indentation for each level of blocks when printing programs
+const INDENT: &str = "    ";
+
+/// Names of every command, used to suggest fixes for typos
+const COMMAND_NAMES: &[&str] = &[
+    "MOVE", "LEFT", "RIGHT", "UP", "DOWN", "FIRE", "TURN", "NOOP", "FORMATION", "JUMP", "IF",
+    "ELSE", "END", "SET", "ADD", "SUB", "MUL", "CMP", "AIM", "DEF", "CALL", "RET", "ON",
+];
+/// Names of every sensor that IF can test
+const SENSOR_NAMES: &[&str] = &[
+    "ENEMY_NEAR", "BULLET_NEAR", "HEALTH_LOW", "WALL_NEAR", "LESS", "EQUAL", "GREATER",
+];
+/// Names of every formation
+const FORMATION_NAMES: &[&str] = &["GATHER", "SPREAD", "LINE", "COLUMN", "WEDGE", "CIRCLE", "BOX"];
+/// Names of every target for AIM
+const TARGET_NAMES: &[&str] = &["NEAREST", "WEAKEST", "LEADER"];
+/// Names of every event that ON can handle
+const EVENT_NAMES: &[&str] = &["HIT", "MEMBER_LOST", "WALL", "KILL"];
+
+/// An error in a line of swarm code, along with the index of the word that caused it
+/// The index is None when the line as a whole is wrong, such as a missing argument
+type LineError = (Option<usize>, GenericError);
 
 /// Represents a single command in the swarm language
 // TODO: Fully design this language
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum SwarmCommand {
     /// Move the swarm forward
     MOVE,
@@ -40,16 +74,274 @@ pub enum SwarmCommand {
     TURN(f32),
     /// Do nothing
     NOOP,
-    /// Move into a formation
-    FORMATION(Formation),
+    /// Move into a formation, with the space between members if given
+    FORMATION(Formation, Option<f32>),
+    /// Store a value in a register
+    SET(Register, Operand),
+    /// Add a value to a register
+    ADD(Register, Operand),
+    /// Subtract a value from a register
+    SUB(Register, Operand),
+    /// Multiply a register by a value
+    MUL(Register, Operand),
+    /// Compare a register to a value, for use by IF LESS/EQUAL/GREATER
+    CMP(Register, Operand),
+    /// Turn toward another swarm
+    AIM(Target),
+}
+
+/// Which swarm AIM turns toward
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Target {
+    /// The closest enemy swarm
+    NEAREST,
+    /// The enemy swarm with the least total health
+    WEAKEST,
+    /// The enemy swarm with the most experience
+    LEADER,
+}
+
+/// Allows conversion of a string to a target
+impl FromStr for Target {
+    /// The type of error returned if the conversion fails
+    /// Must be implemented
+    type Err = GenericError;
+    /// Converts a string to a Target
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "NEAREST" => Ok(Target::NEAREST),
+            "WEAKEST" => Ok(Target::WEAKEST),
+            "LEADER" => Ok(Target::LEADER),
+            _ => Err(GenericError::new("Invalid target name".into())),
+        }
+    }
+}
+
+/// Allows Target to be printed
+impl fmt::Display for Target {
+    /// Writes the target the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::NEAREST => write!(formatter, "NEAREST"),
+            Target::WEAKEST => write!(formatter, "WEAKEST"),
+            Target::LEADER => write!(formatter, "LEADER"),
+        }
+    }
+}
+
+/// A register that a swarm program can store values in
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Register {
+    /// Integer register R0-R3
+    INT(usize),
+    /// Float register F0-F3
+    FLOAT(usize),
+}
+
+/// Allows conversion of a string to a register
+impl FromStr for Register {
+    /// The type of error returned if the conversion fails
+    /// Must be implemented
+    type Err = GenericError;
+    /// Converts a string such as "R0" or "F3" to a Register
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_uppercase();
+        let mut chars = s.chars();
+        // The first letter picks the type of register, the rest is its index
+        let register = match (chars.next(), chars.as_str().parse::<usize>()) {
+            (Some('R'), Ok(index)) if index < NUM_REGISTERS => Register::INT(index),
+            (Some('F'), Ok(index)) if index < NUM_REGISTERS => Register::FLOAT(index),
+            _ => {
+                return Err(GenericError::new(format!(
+                    "Invalid register: {}. Registers are R0-R{} and F0-F{}",
+                    s,
+                    NUM_REGISTERS - 1,
+                    NUM_REGISTERS - 1
+                )))
+            }
+        };
+        Ok(register)
+    }
+}
+
+/// Allows Register to be printed
+impl fmt::Display for Register {
+    /// Writes the register the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::INT(index) => write!(formatter, "R{}", index),
+            Register::FLOAT(index) => write!(formatter, "F{}", index),
+        }
+    }
 }
-#[derive(Clone, Copy, Debug, PartialEq)]
+
+/// A value read by a register command
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operand {
+    /// The value stored in a register
+    REGISTER(Register),
+    /// A number written in the program
+    CONSTANT(f64),
+    /// The direction the swarm is facing in degrees
+    HEADING,
+    /// The number of members in the swarm
+    MEMBERS,
+}
+
+/// Allows conversion of a string to an operand
+impl FromStr for Operand {
+    /// The type of error returned if the conversion fails
+    /// Must be implemented
+    type Err = GenericError;
+    /// Converts a string to an Operand
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "HEADING" => return Ok(Operand::HEADING),
+            "MEMBERS" => return Ok(Operand::MEMBERS),
+            _ => {}
+        }
+        match s.parse::<f64>() {
+            Ok(val) if val.is_finite() => Ok(Operand::CONSTANT(val)),
+            Ok(_) => Err(GenericError::new(format!("Invalid number: {}", s))),
+            Err(_) => match s.parse() {
+                Ok(register) => Ok(Operand::REGISTER(register)),
+                Err(error) => Err(error),
+            },
+        }
+    }
+}
+
+/// Allows Operand to be printed
+impl fmt::Display for Operand {
+    /// Writes the operand the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::REGISTER(register) => write!(formatter, "{}", register),
+            Operand::CONSTANT(value) => write!(formatter, "{}", value),
+            Operand::HEADING => write!(formatter, "HEADING"),
+            Operand::MEMBERS => write!(formatter, "MEMBERS"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 /// A formation
+/// Bullets hit the first member in their path, so members behind others are covered
 pub enum Formation {
     /// Gather together
     GATHER,
     /// Spread apart
     SPREAD,
+    /// Side by side, facing forward
+    /// Every member is exposed from the front
+    LINE,
+    /// One behind another
+    /// A narrow target from the front, but exposed from the sides
+    COLUMN,
+    /// A V with its point facing forward
+    WEDGE,
+    /// Evenly around a circle
+    CIRCLE,
+    /// A square grid
+    /// Members on the inside are covered from every direction
+    BOX,
+}
+
+/// A test of the swarm's surroundings used by IF
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// An enemy swarm is within the given distance
+    ENEMY_NEAR(f32),
+    /// An enemy bullet is within the given distance
+    BULLET_NEAR(f32),
+    /// The swarm has fewer than the given number of members
+    HEALTH_LOW(usize),
+    /// A wall is within the given distance
+    WALL_NEAR(f32),
+    /// The last CMP found the register less than the value
+    LESS,
+    /// The last CMP found the register equal to the value
+    EQUAL,
+    /// The last CMP found the register greater than the value
+    GREATER,
+}
+
+/// Allows conversion of a string to a condition
+impl FromStr for Condition {
+    /// The type of error returned if the conversion fails
+    /// Must be implemented
+    type Err = GenericError;
+    /// Converts a string such as "ENEMY_NEAR 100" to a Condition
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let condition: Vec<&str> = s.split_whitespace().collect();
+        Condition::from_words(&condition).map_err(|(_, error)| error)
+    }
+}
+
+/// Allows Condition to be printed
+impl fmt::Display for Condition {
+    /// Writes the condition the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Condition::ENEMY_NEAR(distance) => write!(formatter, "ENEMY_NEAR {}", distance),
+            Condition::BULLET_NEAR(distance) => write!(formatter, "BULLET_NEAR {}", distance),
+            Condition::HEALTH_LOW(num_members) => write!(formatter, "HEALTH_LOW {}", num_members),
+            Condition::WALL_NEAR(distance) => write!(formatter, "WALL_NEAR {}", distance),
+            Condition::LESS => write!(formatter, "LESS"),
+            Condition::EQUAL => write!(formatter, "EQUAL"),
+            Condition::GREATER => write!(formatter, "GREATER"),
+        }
+    }
+}
+
+/// Functions for Condition
+impl Condition {
+    /// Converts the words following IF to a Condition
+    fn from_words(condition: &[&str]) -> Result<Self, LineError> {
+        if condition.len() == 1 {
+            return match condition[0].to_uppercase().as_str() {
+                "LESS" => Ok(Condition::LESS),
+                "EQUAL" => Ok(Condition::EQUAL),
+                "GREATER" => Ok(Condition::GREATER),
+                _ => Err((Some(0), GenericError::new("Sensor not recognized.".into()))),
+            };
+        }
+        if condition.len() != 2 {
+            return Err((
+                None,
+                GenericError::new(
+                    "Invalid number of arguments for IF. IF requires a sensor and 1 argument"
+                        .into(),
+                ),
+            ));
+        }
+        // Any error in the argument points at the argument
+        let distance = || parse_distance(condition[1]).map_err(|error| (Some(1), error));
+        match condition[0].to_uppercase().as_str() {
+            "ENEMY_NEAR" => Ok(Condition::ENEMY_NEAR(distance()?)),
+            "BULLET_NEAR" => Ok(Condition::BULLET_NEAR(distance()?)),
+            "WALL_NEAR" => Ok(Condition::WALL_NEAR(distance()?)),
+            "HEALTH_LOW" => match condition[1].parse::<usize>() {
+                Ok(count) => Ok(Condition::HEALTH_LOW(count)),
+                Err(_) => Err((
+                    Some(1),
+                    GenericError::new("Invalid member count for HEALTH_LOW.".into()),
+                )),
+            },
+            _ => Err((Some(0), GenericError::new("Sensor not recognized.".into()))),
+        }
+    }
+}
+
+/// Parses a non-negative distance used as a sensor radius
+fn parse_distance(s: &str) -> Result<f32, GenericError> {
+    match s.parse::<f32>() {
+        Ok(val) if val.is_finite() && val >= 0.0 => Ok(val),
+        _ => Err(GenericError::new(
+            "Invalid distance: distances must be non-negative numbers.".into(),
+        )),
+    }
 }
 
 /// Allows conversion of a string to a command
@@ -62,10 +354,76 @@ impl FromStr for Formation {
         match s.to_uppercase().as_str() {
             "GATHER" => Ok(Formation::GATHER),
             "SPREAD" => Ok(Formation::SPREAD),
+            "LINE" => Ok(Formation::LINE),
+            "COLUMN" => Ok(Formation::COLUMN),
+            "WEDGE" => Ok(Formation::WEDGE),
+            "CIRCLE" => Ok(Formation::CIRCLE),
+            "BOX" => Ok(Formation::BOX),
             _ => Err(GenericError::new("Invalid formation name".into())),
         }
     }
 }
+
+/// Allows Formation to be printed
+impl fmt::Display for Formation {
+    /// Writes the formation the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Formation::GATHER => write!(formatter, "GATHER"),
+            Formation::SPREAD => write!(formatter, "SPREAD"),
+            Formation::LINE => write!(formatter, "LINE"),
+            Formation::COLUMN => write!(formatter, "COLUMN"),
+            Formation::WEDGE => write!(formatter, "WEDGE"),
+            Formation::CIRCLE => write!(formatter, "CIRCLE"),
+            Formation::BOX => write!(formatter, "BOX"),
+        }
+    }
+}
+
+/// Something that happens to a swarm, which an ON block can handle
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    /// A bullet hit one of the swarm's members
+    HIT,
+    /// One of the swarm's members was destroyed
+    MEMBER_LOST,
+    /// The swarm ran into the edge of the world
+    WALL,
+    /// One of the swarm's bullets destroyed an enemy member
+    KILL,
+}
+
+/// Allows conversion of a string to an event
+impl FromStr for Event {
+    /// The type of error returned if the conversion fails
+    /// Must be implemented
+    type Err = GenericError;
+    /// Converts a string to an Event
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "HIT" => Ok(Event::HIT),
+            "MEMBER_LOST" => Ok(Event::MEMBER_LOST),
+            "WALL" => Ok(Event::WALL),
+            "KILL" => Ok(Event::KILL),
+            _ => Err(GenericError::new("Invalid event name".into())),
+        }
+    }
+}
+
+/// Allows Event to be printed
+impl fmt::Display for Event {
+    /// Writes the event the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::HIT => write!(formatter, "HIT"),
+            Event::MEMBER_LOST => write!(formatter, "MEMBER_LOST"),
+            Event::WALL => write!(formatter, "WALL"),
+            Event::KILL => write!(formatter, "KILL"),
+        }
+    }
+}
+
 /// Allows conversion of a string to a command
 impl FromStr for SwarmCommand {
     /// The type of error returned if the conversion fails
@@ -73,16 +431,80 @@ impl FromStr for SwarmCommand {
     type Err = GenericError;
     /// Converts a string to a SwarmCommand
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // TODO: Parse a line of swarm code as an enum
-
         let command: Vec<&str> = s.trim().split_whitespace().collect();
 
-        if (command.len() == 0) {
+        if command.len() == 0 {
             return Err(GenericError {
                 description: "Command is white space (should be non-error).".into(),
             });
         };
 
+        SwarmCommand::from_words(&command).map_err(|(_, error)| error)
+    }
+}
+
+/// Allows SwarmCommand to be printed
+impl fmt::Display for SwarmCommand {
+    /// Writes the command the way it is parsed
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SwarmCommand::MOVE => write!(formatter, "MOVE"),
+            SwarmCommand::LEFT => write!(formatter, "LEFT"),
+            SwarmCommand::RIGHT => write!(formatter, "RIGHT"),
+            SwarmCommand::UP => write!(formatter, "UP"),
+            SwarmCommand::DOWN => write!(formatter, "DOWN"),
+            SwarmCommand::FIRE => write!(formatter, "FIRE"),
+            SwarmCommand::TURN(turn_amt) => write!(formatter, "TURN {}", turn_amt),
+            SwarmCommand::NOOP => write!(formatter, "NOOP"),
+            SwarmCommand::FORMATION(formation, None) => write!(formatter, "FORMATION {}", formation),
+            SwarmCommand::FORMATION(formation, Some(spacing)) => {
+                write!(formatter, "FORMATION {} {}", formation, spacing)
+            }
+            SwarmCommand::SET(register, operand) => write!(formatter, "SET {} {}", register, operand),
+            SwarmCommand::ADD(register, operand) => write!(formatter, "ADD {} {}", register, operand),
+            SwarmCommand::SUB(register, operand) => write!(formatter, "SUB {} {}", register, operand),
+            SwarmCommand::MUL(register, operand) => write!(formatter, "MUL {} {}", register, operand),
+            SwarmCommand::CMP(register, operand) => write!(formatter, "CMP {} {}", register, operand),
+            SwarmCommand::AIM(target) => write!(formatter, "AIM {}", target),
+        }
+    }
+}
+
+/// Functions for SwarmCommand
+impl SwarmCommand {
+    /// Number of cycles the command uses from a swarm's per-tick budget
+    pub fn cost(&self) -> usize {
+        match *self {
+            SwarmCommand::SET(_, _) |
+            SwarmCommand::ADD(_, _) |
+            SwarmCommand::SUB(_, _) |
+            SwarmCommand::MUL(_, _) |
+            SwarmCommand::CMP(_, _) |
+            SwarmCommand::NOOP => 1,
+            SwarmCommand::TURN(_) | SwarmCommand::AIM(_) => 3,
+            SwarmCommand::MOVE |
+            SwarmCommand::LEFT |
+            SwarmCommand::RIGHT |
+            SwarmCommand::UP |
+            SwarmCommand::DOWN => 4,
+            SwarmCommand::FIRE => 5,
+            SwarmCommand::FORMATION(_, _) => 8,
+        }
+    }
+    /// Whether the command acts on the world, which ends the swarm's tick
+    /// Register commands only touch the program, so they can chain within a tick
+    pub fn ends_tick(&self) -> bool {
+        match *self {
+            SwarmCommand::SET(_, _) |
+            SwarmCommand::ADD(_, _) |
+            SwarmCommand::SUB(_, _) |
+            SwarmCommand::MUL(_, _) |
+            SwarmCommand::CMP(_, _) => false,
+            _ => true,
+        }
+    }
+    /// Converts the words of a non-empty line to a SwarmCommand
+    fn from_words(command: &[&str]) -> Result<Self, LineError> {
         // Match
         match command[0].to_uppercase().as_str() {
             "MOVE" => Ok(SwarmCommand::MOVE), // Move command case
@@ -99,43 +521,100 @@ impl FromStr for SwarmCommand {
                     match command[1].parse::<f32>() {
                         Ok(val) => {
                             if val.is_normal() {
-                                if (val.abs() <= 30.0_f32) {
+                                if val.abs() <= MAX_TURN {
                                     Ok(SwarmCommand::TURN(val)) // If value satisfies clamp conditions,
                                 } else {
-                                    Err(GenericError::new(
-                                        "Input parameter float should range from -30.0 to 30.0."
-                                            .into(),
+                                    Err((
+                                        Some(1),
+                                        GenericError::new(
+                                            "Input parameter float should range from -30.0 to 30.0."
+                                                .into(),
+                                        ),
                                     )) // Otherwise, throw compilation error
                                 }
                             } else {
-                                Err(GenericError::new(
-                                    "Invalid float parameter for TURN.".into(), // If parameter is not normal, throw error
+                                Err((
+                                    Some(1),
+                                    GenericError::new(
+                                        "Invalid float parameter for TURN.".into(), // If parameter is not normal, throw error
+                                    ),
                                 ))
                             }
                         }
 
-                        Err(_) => Err(GenericError::new(
-                            "Invalid float parameter for TURN.".into(),
+                        Err(_) => Err((
+                            Some(1),
+                            GenericError::new("Invalid float parameter for TURN.".into()),
                         )), // If parameter cannot be converted to float, throw error
                     }
                 } else {
-                    Err(GenericError::new("No parameters found for TURN.".into())) // No parameter provided
+                    Err((
+                        None,
+                        GenericError::new("No parameters found for TURN.".into()),
+                    )) // No parameter provided
                 }
             }
-            "FORMATION" => {
+            "SET" | "ADD" | "SUB" | "MUL" | "CMP" => {
+                if command.len() == 3 {
+                    let register: Register = command[1].parse().map_err(|error| (Some(1), error))?;
+                    let operand: Operand = command[2].parse().map_err(|error| (Some(2), error))?;
+                    Ok(match command[0].to_uppercase().as_str() {
+                        "SET" => SwarmCommand::SET(register, operand),
+                        "ADD" => SwarmCommand::ADD(register, operand),
+                        "SUB" => SwarmCommand::SUB(register, operand),
+                        "MUL" => SwarmCommand::MUL(register, operand),
+                        _ => SwarmCommand::CMP(register, operand),
+                    })
+                } else {
+                    Err((
+                        None,
+                        GenericError::new(format!(
+                            "Invalid number of arguments for command {}. {} requires a register and a value",
+                            command[0].to_uppercase(),
+                            command[0].to_uppercase()
+                        )),
+                    ))
+                }
+            }
+            "AIM" => {
                 if command.len() == 2 {
+                    match command[1].parse() {
+                        Ok(target) => Ok(SwarmCommand::AIM(target)),
+                        Err(error) => Err((Some(1), error)),
+                    }
+                } else {
+                    Err((None, GenericError::new("Invalid number of arguments for command AIM. AIM requires 1 argument".into())))
+                }
+            }
+            "FORMATION" => {
+                if command.len() == 2 || command.len() == 3 {
                     let formation: Formation = match command[1].parse() {
                         Ok(formation) => formation,
-                        Err(err) => return Err(err),
+                        Err(err) => return Err((Some(1), err)),
+                    };
+                    // Spacing is optional
+                    let spacing = match command.get(2).map(|spacing| spacing.parse::<f32>()) {
+                        None => None,
+                        Some(Ok(spacing)) if spacing > 0.0 && spacing <= MAX_SPACING => Some(spacing),
+                        Some(_) => {
+                            return Err((
+                                Some(2),
+                                GenericError::new(format!(
+                                    "Invalid spacing: spacing must be more than 0 and at most {}.",
+                                    MAX_SPACING
+                                )),
+                            ))
+                        }
                     };
-                    Ok(SwarmCommand::FORMATION(formation))
+                    Ok(SwarmCommand::FORMATION(formation, spacing))
                 } else {
-                    Err(GenericError::new("Invalid number of arguments for command FORMATION. FORMATION requires 1 argument".into()))
+                    Err((None, GenericError::new("Invalid number of arguments for command FORMATION. FORMATION requires a formation and optionally a spacing".into())))
                 }
             }
-            _ => Err(
+            _ => Err((
+                Some(0),
                 GenericError::new("Command not recognized.".into()), // Invalid command case
-            ),
+            )),
         }
     }
 }
@@ -164,23 +643,543 @@ fn test_verifier() {
     assert_eq!(c3, SwarmCommand::TURN(-29.5));
 } END BROKEN IN MERGE */
 
+/// A statement in the syntax tree of a swarm program
+/// Each statement starts with the line it was parsed from
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Statement {
+    /// A command that runs on its own
+    COMMAND(usize, SwarmCommand),
+    /// A label that jumps can continue at
+    LABEL(usize, String),
+    /// Continue execution somewhere else
+    JUMP(usize, JumpTarget),
+    /// Run a procedure
+    CALL(usize, String),
+    /// Return from a procedure early
+    RET(usize),
+    /// Statements to run if a condition holds
+    /// Followed by the line of the ELSE and its statements, if there is one
+    IF(usize, Condition, Vec<Statement>, Option<(usize, Vec<Statement>)>),
+}
+
+/// Where a JUMP continues
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum JumpTarget {
+    /// The command after a label
+    LABEL(String),
+    /// The command with the given index in the body the jump is in
+    /// Every line but labels, ELSE and END is a command
+    INDEX(usize),
+}
+
+/// Compares statements
+impl PartialEq for Statement {
+    /// Statements are equal if they do the same thing, wherever they are in the source
+    fn eq(&self, other: &Statement) -> bool {
+        match (self, other) {
+            (&Statement::COMMAND(_, a), &Statement::COMMAND(_, b)) => a == b,
+            (&Statement::LABEL(_, ref a), &Statement::LABEL(_, ref b)) => a == b,
+            (&Statement::JUMP(_, ref a), &Statement::JUMP(_, ref b)) => a == b,
+            (&Statement::CALL(_, ref a), &Statement::CALL(_, ref b)) => a == b,
+            (&Statement::RET(_), &Statement::RET(_)) => true,
+            (
+                &Statement::IF(_, a, ref a_then, ref a_else),
+                &Statement::IF(_, b, ref b_then, ref b_else),
+            ) => {
+                a == b && a_then == b_then &&
+                    a_else.as_ref().map(|&(_, ref statements)| statements) ==
+                        b_else.as_ref().map(|&(_, ref statements)| statements)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Functions for Statement
+impl Statement {
+    /// Writes the statement on its own lines, indented by depth levels
+    fn write(&self, formatter: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let indent = INDENT.repeat(depth);
+        match *self {
+            Statement::COMMAND(_, command) => writeln!(formatter, "{}{}", indent, command),
+            Statement::LABEL(_, ref name) => writeln!(formatter, "{}{}:", indent, name),
+            Statement::JUMP(_, JumpTarget::LABEL(ref name)) => {
+                writeln!(formatter, "{}JUMP {}", indent, name)
+            }
+            Statement::JUMP(_, JumpTarget::INDEX(index)) => {
+                writeln!(formatter, "{}JUMP {}", indent, index)
+            }
+            Statement::CALL(_, ref name) => writeln!(formatter, "{}CALL {}", indent, name),
+            Statement::RET(_) => writeln!(formatter, "{}RET", indent),
+            Statement::IF(_, condition, ref then, ref otherwise) => {
+                writeln!(formatter, "{}IF {}", indent, condition)?;
+                write_statements(formatter, then, depth + 1)?;
+                if let Some((_, ref otherwise)) = *otherwise {
+                    writeln!(formatter, "{}ELSE", indent)?;
+                    write_statements(formatter, otherwise, depth + 1)?;
+                }
+                writeln!(formatter, "{}END", indent)
+            }
+        }
+    }
+}
+
+/// Writes a list of statements, indented by depth levels
+fn write_statements(
+    formatter: &mut fmt::Formatter,
+    statements: &[Statement],
+    depth: usize,
+) -> fmt::Result {
+    for statement in statements {
+        statement.write(formatter, depth)?;
+    }
+    Ok(())
+}
+
+/// A procedure defined with DEF
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Procedure {
+    /// Line of the DEF
+    pub line: usize,
+    /// Name used to CALL the procedure
+    pub name: String,
+    /// Statements in the procedure
+    pub body: Vec<Statement>,
+    /// Line of the END, where the procedure returns
+    pub end_line: usize,
+}
+
+/// Compares procedures
+impl PartialEq for Procedure {
+    /// Procedures are equal if they have the same name and statements
+    fn eq(&self, other: &Procedure) -> bool {
+        self.name == other.name && self.body == other.body
+    }
+}
+
+/// A block defined with ON, which runs when an event happens
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Handler {
+    /// Line of the ON
+    pub line: usize,
+    /// Event that starts the handler
+    pub event: Event,
+    /// Statements in the handler
+    pub body: Vec<Statement>,
+    /// Line of the END, where the program resumes
+    pub end_line: usize,
+}
+
+/// Compares handlers
+impl PartialEq for Handler {
+    /// Handlers are equal if they handle the same event with the same statements
+    fn eq(&self, other: &Handler) -> bool {
+        self.event == other.event && self.body == other.body
+    }
+}
+
 /// A swarm program is a list of swarm commands
-#[derive(Clone, Debug)]
+/// It is parsed to statements, then compiled to instructions for the VM
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SwarmProgram {
-    /// The list of commands
-    pub commands: Vec<SwarmCommand>,
+    /// The statements of the main body
+    pub statements: Vec<Statement>,
+    /// The procedures that can be called
+    pub procedures: Vec<Procedure>,
+    /// The handlers for events
+    pub handlers: Vec<Handler>,
+    /// The compiled instructions
+    pub bytecode: Vec<Instruction>,
 
-    /// Program counter pointing to current command
+    /// Program counter pointing to current instruction
     pub program_counter: usize,
+    /// Integer registers R0-R3
+    pub int_registers: [i64; NUM_REGISTERS],
+    /// Float registers F0-F3
+    pub float_registers: [f32; NUM_REGISTERS],
+    /// Result of the last CMP, if any
+    #[serde(with = "comparison_serde")]
+    pub comparison: Option<Ordering>,
+    /// The source the program was compiled from, exactly as it was written
+    /// Empty if the program wasn't compiled from source
+    #[serde(default)]
+    pub source: String,
+    /// Line of the source each instruction was compiled from
+    /// Empty if the program wasn't compiled from source
+    pub source_lines: Vec<usize>,
+    /// Number of instructions before the procedures
+    /// The program loops back to the start after running these
+    pub main_length: usize,
+    /// Instructions to return to from the procedures that are in progress
+    pub call_stack: Vec<usize>,
+    /// Error that stopped the program, if any
+    pub fault: Option<RuntimeFault>,
+    /// Index of the first instruction of each handler
+    pub handler_entries: Vec<(Event, usize)>,
+    /// Size of the call stack when the running handler started, if one is running
+    pub handler_depth: Option<usize>,
+}
+
+/// Stores the result of a comparison as -1, 0 or 1
+mod comparison_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::cmp::Ordering;
+
+    /// Writes a comparison as a number
+    pub fn serialize<S>(comparison: &Option<Ordering>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        comparison.map(|ordering| ordering as i8).serialize(serializer)
+    }
+    /// Reads a comparison from a number
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Ordering>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<i8>::deserialize(deserializer)?.map(|value| value.cmp(&0)))
+    }
+}
+
+/// An error that stops a swarm program while it runs
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RuntimeFault {
+    /// Too many procedure calls were in progress at once
+    #[serde(rename = "stack overflow")]
+    STACK_OVERFLOW,
+    /// A procedure returned without being called
+    #[serde(rename = "stack underflow")]
+    STACK_UNDERFLOW,
+}
+
+/// Allows RuntimeFault to be printed
+impl fmt::Display for RuntimeFault {
+    /// Writes the fault using a formatter
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeFault::STACK_OVERFLOW => write!(
+                formatter,
+                "Stack overflow: more than {} procedure calls in progress",
+                MAX_CALL_DEPTH
+            ),
+            RuntimeFault::STACK_UNDERFLOW => {
+                write!(formatter, "Stack underflow: RET without matching CALL")
+            }
+        }
+    }
+}
+
+/// Compares programs
+impl PartialEq for SwarmProgram {
+    /// Programs are equal if they have the same code
+    /// Source lines and the state of a running program are ignored
+    fn eq(&self, other: &SwarmProgram) -> bool {
+        self.statements == other.statements && self.procedures == other.procedures &&
+            self.handlers == other.handlers
+    }
+}
+
+/// Allows SwarmProgram to be printed
+/// The output is the canonical form of the program, which parses back to an equal program
+impl fmt::Display for SwarmProgram {
+    /// Writes the main body, then each procedure, then each handler
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write_statements(formatter, &self.statements, 0)?;
+        for procedure in self.procedures.iter() {
+            writeln!(formatter, "DEF {}", procedure.name)?;
+            write_statements(formatter, &procedure.body, 1)?;
+            writeln!(formatter, "END")?;
+        }
+        for handler in self.handlers.iter() {
+            writeln!(formatter, "ON {}", handler.event)?;
+            write_statements(formatter, &handler.body, 1)?;
+            writeln!(formatter, "END")?;
+        }
+        Ok(())
+    }
 }
 
 /// Some functions for SwarmProgram
 impl SwarmProgram {
     /// Constructor (empty)
+    /// Builds a program that runs the given commands in a loop
     pub fn new(commands: Vec<SwarmCommand>) -> Self {
+        let statements = commands
+            .into_iter()
+            .map(|command| Statement::COMMAND(0, command))
+            .collect();
+        let mut program = SwarmProgram::compile(statements, Vec::new(), Vec::new());
+        // There is no source to map back to
+        program.source_lines.clear();
+        program
+    }
+    /// Builds a program from parsed statements, procedures and handlers
+    pub fn compile(
+        statements: Vec<Statement>,
+        procedures: Vec<Procedure>,
+        handlers: Vec<Handler>,
+    ) -> Self {
+        let (bytecode, source_lines, main_length, handler_entries) =
+            vm::compile(&statements, &procedures, &handlers);
         SwarmProgram {
-            commands: commands,
+            statements: statements,
+            procedures: procedures,
+            handlers: handlers,
+            bytecode: bytecode,
             program_counter: 0,
+            int_registers: [0; NUM_REGISTERS],
+            float_registers: [0.0; NUM_REGISTERS],
+            comparison: None,
+            source: String::new(),
+            source_lines: source_lines,
+            main_length: main_length,
+            call_stack: Vec::new(),
+            fault: None,
+            handler_entries: handler_entries,
+            handler_depth: None,
+        }
+    }
+    /// Index of the instruction that follows the current one
+    /// The end of the main body wraps around to the start
+    pub fn next_counter(&self) -> usize {
+        self.next_index(self.program_counter)
+    }
+    /// Index of the instruction that follows the given one
+    pub fn next_index(&self, index: usize) -> usize {
+        let next = index + 1;
+        if next == self.main_length || next >= self.bytecode.len() {
+            0
+        } else {
+            next
+        }
+    }
+    /// Line of the source that the next instruction was compiled from
+    /// Idle programs have no next instruction
+    pub fn current_line(&self) -> Option<usize> {
+        if self.is_idle() {
+            return None;
+        }
+        self.source_lines.get(self.program_counter).cloned()
+    }
+    /// Whether the program is waiting for an event
+    /// Programs with only DEF and ON blocks have no main body, so that's all they do
+    pub fn is_idle(&self) -> bool {
+        self.main_length == 0 && self.program_counter == 0 && self.call_stack.is_empty()
+    }
+    /// Puts the program back in its initial state
+    /// Rewinds the program counter, clears all registers and the call stack
+    pub fn reset(&mut self) {
+        self.program_counter = 0;
+        self.int_registers = [0; NUM_REGISTERS];
+        self.float_registers = [0.0; NUM_REGISTERS];
+        self.comparison = None;
+        self.call_stack.clear();
+        self.fault = None;
+        self.handler_depth = None;
+    }
+    /// Starts the handler for an event, saving the program counter to resume at
+    /// Events are ignored while a handler runs, or if there's no room to save the counter
+    /// Returns whether a handler was started
+    pub fn interrupt(&mut self, event: Event) -> bool {
+        if self.handler_depth.is_some() || self.fault.is_some() ||
+            self.call_stack.len() >= MAX_CALL_DEPTH
+        {
+            return false;
+        }
+        match self.handler_entries.iter().find(|&&(handled, _)| handled == event) {
+            Some(&(_, entry)) => {
+                self.handler_depth = Some(self.call_stack.len());
+                self.call_stack.push(self.program_counter);
+                self.program_counter = entry;
+                true
+            }
+            None => false,
+        }
+    }
+    /// Reads the value of a register
+    pub fn get(&self, register: Register) -> f64 {
+        match register {
+            Register::INT(index) => self.int_registers[index] as f64,
+            Register::FLOAT(index) => self.float_registers[index] as f64,
+        }
+    }
+    /// Writes a value to a register
+    /// Values written to integer registers are truncated
+    /// Values out of a register's range are clamped to it and NaN becomes 0,
+    /// so registers always hold values a snapshot can store
+    pub fn set(&mut self, register: Register, value: f64) {
+        let value: f64 = if value.is_nan() { 0.0 } else { value };
+        match register {
+            Register::INT(index) => {
+                self.int_registers[index] = value.max(i64::MIN as f64).min(i64::MAX as f64) as i64
+            }
+            Register::FLOAT(index) => {
+                self.float_registers[index] = value.max(f32::MIN as f64).min(f32::MAX as f64) as f32
+            }
+        }
+    }
+}
+
+/// A parsed line of swarm code
+#[derive(Clone, Debug)]
+enum Line<'a> {
+    /// A command that runs on its own
+    COMMAND(SwarmCommand),
+    /// A label definition
+    LABEL(&'a str),
+    /// A jump to a label or index
+    JUMP(JumpTarget),
+    /// A call to a procedure
+    CALL(&'a str),
+    /// A return from a procedure
+    RET,
+    /// The start of an IF block
+    IF(Condition),
+    /// The start of the ELSE branch of an IF block
+    ELSE,
+    /// The start of a procedure definition
+    DEF(&'a str),
+    /// The start of an event handler
+    ON(Event),
+    /// The end of an IF block, procedure definition or event handler
+    END,
+    /// The start of an IF, DEF or ON block whose line has an error
+    /// Kept so the rest of the block still lines up with its END
+    BROKEN(&'static str),
+}
+
+/// Functions for Line
+impl<'a> Line<'a> {
+    /// Converts the words of a non-empty line to a Line
+    fn from_words(words: &[&'a str]) -> Result<Self, LineError> {
+        // Keywords that take exactly one argument
+        let argument = || {
+            if words.len() == 2 {
+                Ok(words[1])
+            } else {
+                let keyword = words[0].to_uppercase();
+                Err((
+                    None,
+                    GenericError::new(format!(
+                        "Invalid number of arguments for command {}. {} requires 1 argument",
+                        keyword, keyword
+                    )),
+                ))
+            }
+        };
+        // Keywords that take no arguments
+        let alone = |line: Line<'a>| {
+            if words.len() == 1 {
+                Ok(line)
+            } else {
+                Err((
+                    Some(1),
+                    GenericError::new(format!("{} takes no arguments", words[0].to_uppercase())),
+                ))
+            }
+        };
+        match words[0].to_uppercase().as_str() {
+            _ if words[words.len() - 1].ends_with(':') => {
+                let label = words[0].trim_right_matches(':');
+                if words.len() == 1 && is_valid_label(label) {
+                    Ok(Line::LABEL(label))
+                } else {
+                    Err((
+                        None,
+                        GenericError::new(format!(
+                            "Invalid label name: {}",
+                            words.join(" ").trim_right_matches(':')
+                        )),
+                    ))
+                }
+            }
+            "JUMP" => {
+                let target = argument()?;
+                // Anything that isn't an index refers to a label
+                match target.parse::<usize>() {
+                    Ok(index) => Ok(Line::JUMP(JumpTarget::INDEX(index))),
+                    Err(_) => Ok(Line::JUMP(JumpTarget::LABEL(target.into()))),
+                }
+            }
+            "CALL" => Ok(Line::CALL(argument()?)),
+            "DEF" => {
+                let name = argument()?;
+                if is_valid_label(name) {
+                    Ok(Line::DEF(name))
+                } else {
+                    Err((
+                        Some(1),
+                        GenericError::new(format!("Invalid procedure name: {}", name)),
+                    ))
+                }
+            }
+            "ON" => match argument()?.parse() {
+                Ok(event) => Ok(Line::ON(event)),
+                Err(error) => Err((Some(1), error)),
+            },
+            "IF" => match Condition::from_words(&words[1..]) {
+                Ok(condition) => Ok(Line::IF(condition)),
+                // Shift the error past the IF
+                Err((index, error)) => Err((index.map(|index| index + 1), error)),
+            },
+            "RET" => alone(Line::RET),
+            "ELSE" => alone(Line::ELSE),
+            "END" => alone(Line::END),
+            _ => Ok(Line::COMMAND(SwarmCommand::from_words(words)?)),
+        }
+    }
+}
+
+/// A block that is still being parsed
+enum OpenBlock {
+    /// IF with its line, condition, statements so far, and the ELSE line and statements if seen
+    IF(usize, Condition, Vec<Statement>, Option<(usize, Vec<Statement>)>),
+    /// DEF with its line, name and statements so far
+    DEF(usize, String, Vec<Statement>),
+    /// ON with its line, event and statements so far
+    ON(usize, Event, Vec<Statement>),
+    /// A block whose first line has an error, with its line, keyword and statements so far
+    /// Never compiled, since the program has an error anyway
+    BROKEN(usize, &'static str, Vec<Statement>),
+}
+
+/// Functions for OpenBlock
+impl OpenBlock {
+    /// The list that statements in the block are currently added to
+    fn statements(&mut self) -> &mut Vec<Statement> {
+        match *self {
+            OpenBlock::IF(_, _, _, Some((_, ref mut otherwise))) => otherwise,
+            OpenBlock::IF(_, _, ref mut then, None) => then,
+            OpenBlock::DEF(_, _, ref mut body) => body,
+            OpenBlock::ON(_, _, ref mut body) => body,
+            OpenBlock::BROKEN(_, _, ref mut body) => body,
+        }
+    }
+}
+
+/// The labels and jumps of the main body, a procedure or a handler
+/// Jumps can only reach labels and commands in the same body
+struct Scope<'a> {
+    /// What the body is, for diagnostics
+    kind: &'static str,
+    /// Labels defined in the body
+    labels: Vec<&'a str>,
+    /// Jumps in the body, with their line and the word of their target
+    jumps: Vec<(usize, Token<'a>, JumpTarget)>,
+    /// Number of commands in the body, which jumps to an index count
+    commands: usize,
+}
+
+/// Functions for Scope
+impl<'a> Scope<'a> {
+    /// Constructor for a body with nothing in it yet
+    fn new(kind: &'static str) -> Self {
+        Scope {
+            kind: kind,
+            labels: Vec::new(),
+            jumps: Vec::new(),
+            commands: 0,
         }
     }
 }
@@ -189,37 +1188,451 @@ impl SwarmProgram {
 impl FromStr for SwarmProgram {
     /// The type of error returned if the conversion fails
     /// Must be implemented
-    type Err = GenericError;
-    /// Converts a string to a SwarmProgram
+    type Err = CompileError;
+    /// Converts a string to a SwarmProgram with the default length limit
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // TODO: Split the input and use SwarmCommand's from_str
+        SwarmProgram::from_source(s, MAX_NUM_COMMANDS)
+    }
+}
 
-        // Vector of SwarmCommands
-        let mut command_list: Vec<SwarmCommand> = Vec::new();
+/// Functions for parsing a SwarmProgram
+impl SwarmProgram {
+    /// Converts source code to a SwarmProgram with at most the given number of instructions
+    /// Every error in the program is reported, not just the first
+    pub fn from_source(s: &str, max_length: usize) -> Result<Self, CompileError> {
+        // Problems found so far
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        // Non-empty lines with their line numbers
+        let mut lines: Vec<(usize, Vec<Token>)> = Vec::new();
+        // Names of every procedure, so they can be called before they are defined
+        let mut procedures: Vec<&str> = Vec::new();
+        // Events that have a handler
+        let mut events: Vec<Event> = Vec::new();
 
-        // Turn lines into commands
-        for line in s.trim().lines() {
-            command_list.push(match line.parse() {
-                Ok(comm) => comm, // If the command is valid, add it to the list
-                Err(error) => {
-                    if line.trim().is_empty() {
+        // Split the program into words
+        for (line_number, line) in s.lines().enumerate().map(|(index, line)| (index + 1, line)) {
+            let tokens = tokenize(strip_comment(line));
+            if !tokens.is_empty() {
+                lines.push((line_number, tokens));
+            }
+        }
+
+        // Parse each line on its own
+        let mut parsed: Vec<(usize, &[Token], Line)> = Vec::new();
+        for &(line_number, ref tokens) in lines.iter() {
+            let words: Vec<&str> = tokens.iter().map(|token| token.text).collect();
+            let (start, end) = span(tokens);
+            match Line::from_words(&words) {
+                Ok(line) => {
+                    // Check names are only defined once
+                    // Labels are checked once the body they're in is known
+                    let duplicate = match line {
+                        Line::DEF(name) if procedures.contains(&name) => Some(("procedure", "DEF")),
+                        Line::DEF(name) => {
+                            procedures.push(name);
+                            None
+                        }
+                        Line::ON(event) if events.contains(&event) => Some(("handler", "ON")),
+                        Line::ON(event) => {
+                            events.push(event);
+                            None
+                        }
+                        _ => None,
+                    };
+                    match duplicate {
+                        Some((kind, keyword)) => {
+                            diagnostics.push(Diagnostic::error(
+                                line_number,
+                                start,
+                                end,
+                                format!("Duplicate {}: {}", kind, words[words.len() - 1]),
+                            ));
+                            parsed.push((line_number, tokens, Line::BROKEN(keyword)));
+                        }
+                        None => parsed.push((line_number, tokens, line)),
+                    }
+                }
+                Err((index, error)) => {
+                    // Point at the word that caused the error, or the whole line
+                    let (start, end) = match index {
+                        Some(index) if index < tokens.len() => {
+                            (tokens[index].start, tokens[index].end)
+                        }
+                        _ => (start, end),
+                    };
+                    diagnostics.push(
+                        Diagnostic::error(line_number, start, end, error.description)
+                            .with_suggestion(suggest(&words, index, &[], &[])),
+                    );
+                    // A block with a bad first line still needs its END, so one mistake is one error
+                    let keyword = match words[0].to_uppercase().as_str() {
+                        "IF" => Some("IF"),
+                        "DEF" => Some("DEF"),
+                        "ON" => Some("ON"),
+                        _ => None,
+                    };
+                    if let Some(keyword) = keyword {
+                        // Calls to the procedure are already covered by the error
+                        if keyword == "DEF" && words.len() > 1 && !procedures.contains(&words[1]) {
+                            procedures.push(words[1]);
+                        }
+                        parsed.push((line_number, tokens, Line::BROKEN(keyword)));
+                    }
+                }
+            }
+        }
+
+        // Statements of the main body
+        let mut statements: Vec<Statement> = Vec::new();
+        // Procedures and handlers defined so far
+        let mut definitions: Vec<Procedure> = Vec::new();
+        let mut handlers: Vec<Handler> = Vec::new();
+        // Blocks that haven't been closed yet, with the columns they were opened at
+        let mut blocks: Vec<(OpenBlock, (usize, usize))> = Vec::new();
+        // The body being parsed is last, after the main body
+        let mut scopes: Vec<Scope> = vec![Scope::new("main body")];
+        // Bodies that have been closed, whose jumps are checked once every label is known
+        let mut closed_scopes: Vec<Scope> = Vec::new();
+
+        // Build the syntax tree from the lines
+        for (line_number, tokens, line) in parsed {
+            let words: Vec<&str> = tokens.iter().map(|token| token.text).collect();
+            let (start, end) = span(tokens);
+            // Reports an error on a word of the line
+            let mut error = |index: usize, message: String, suggestion: Option<String>| {
+                diagnostics.push(
+                    Diagnostic::error(line_number, tokens[index].start, tokens[index].end, message)
+                        .with_suggestion(suggestion),
+                );
+            };
+            // Every line that compiles to an instruction is a command
+            match line {
+                Line::COMMAND(_) | Line::JUMP(_) | Line::CALL(_) | Line::RET | Line::IF(_) |
+                Line::BROKEN("IF") => {
+                    if let Some(scope) = scopes.last_mut() {
+                        scope.commands += 1;
+                    }
+                }
+                _ => {}
+            }
+            let statement = match line {
+                Line::COMMAND(command) => Statement::COMMAND(line_number, command),
+                Line::LABEL(name) => {
+                    if let Some(scope) = scopes.last_mut() {
+                        if scope.labels.contains(&name) {
+                            error(0, format!("Duplicate label: {}", name), None);
+                            continue;
+                        }
+                        scope.labels.push(name);
+                    }
+                    Statement::LABEL(line_number, name.into())
+                }
+                Line::JUMP(target) => {
+                    if let Some(scope) = scopes.last_mut() {
+                        scope.jumps.push((line_number, tokens[1], target.clone()));
+                    }
+                    Statement::JUMP(line_number, target)
+                }
+                Line::CALL(name) => {
+                    if !procedures.contains(&name) {
+                        error(
+                            1,
+                            format!("Unknown procedure for CALL: {}", name),
+                            suggest(&words, Some(1), &[], &procedures),
+                        );
+                    }
+                    Statement::CALL(line_number, name.into())
+                }
+                Line::RET => {
+                    let in_procedure = blocks.iter().any(|&(ref block, _)| match *block {
+                        OpenBlock::DEF(_, _, _) | OpenBlock::ON(_, _, _) => true,
+                        OpenBlock::BROKEN(_, keyword, _) => keyword != "IF",
+                        _ => false,
+                    });
+                    if !in_procedure {
+                        error(0, "RET outside of a procedure or handler".into(), None);
+                    }
+                    Statement::RET(line_number)
+                }
+                // Blocks are added once they are closed
+                Line::IF(condition) => {
+                    blocks.push((OpenBlock::IF(line_number, condition, Vec::new(), None), (start, end)));
+                    continue;
+                }
+                Line::DEF(name) => {
+                    if !blocks.is_empty() {
+                        error(0, "DEF can't be inside another block".into(), None);
+                    }
+                    blocks.push((OpenBlock::DEF(line_number, name.into(), Vec::new()), (start, end)));
+                    scopes.push(Scope::new("procedure"));
+                    continue;
+                }
+                Line::ON(event) => {
+                    if !blocks.is_empty() {
+                        error(0, "ON can't be inside another block".into(), None);
+                    }
+                    blocks.push((OpenBlock::ON(line_number, event, Vec::new()), (start, end)));
+                    scopes.push(Scope::new("handler"));
+                    continue;
+                }
+                Line::BROKEN(keyword) => {
+                    blocks.push((OpenBlock::BROKEN(line_number, keyword, Vec::new()), (start, end)));
+                    match keyword {
+                        "DEF" => scopes.push(Scope::new("procedure")),
+                        "ON" => scopes.push(Scope::new("handler")),
+                        _ => {}
+                    }
+                    continue;
+                }
+                Line::ELSE => {
+                    match blocks.last_mut() {
+                        Some(&mut (OpenBlock::IF(_, _, _, ref mut otherwise @ None), _)) => {
+                            *otherwise = Some((line_number, Vec::new()));
+                        }
+                        Some(&mut (OpenBlock::BROKEN(_, "IF", _), _)) => {}
+                        _ => error(0, "ELSE without matching IF".into(), None),
+                    }
+                    continue;
+                }
+                Line::END => match blocks.pop() {
+                    Some((OpenBlock::IF(if_line, condition, then, otherwise), _)) => {
+                        Statement::IF(if_line, condition, then, otherwise)
+                    }
+                    Some((OpenBlock::DEF(def_line, name, body), _)) => {
+                        definitions.push(Procedure {
+                            line: def_line,
+                            name: name,
+                            body: body,
+                            end_line: line_number,
+                        });
+                        closed_scopes.extend(scopes.pop());
                         continue;
-                    } else {
-                        return Err(error);
                     }
-                } // If the command is invalid, throw an error
-            });
+                    Some((OpenBlock::ON(on_line, event, body), _)) => {
+                        handlers.push(Handler {
+                            line: on_line,
+                            event: event,
+                            body: body,
+                            end_line: line_number,
+                        });
+                        closed_scopes.extend(scopes.pop());
+                        continue;
+                    }
+                    Some((OpenBlock::BROKEN(_, keyword, _), _)) => {
+                        if keyword != "IF" {
+                            closed_scopes.extend(scopes.pop());
+                        }
+                        continue;
+                    }
+                    None => {
+                        error(0, "END without matching IF, DEF or ON".into(), None);
+                        continue;
+                    }
+                },
+            };
+            // Add the statement to the innermost open block
+            match blocks.last_mut() {
+                Some(&mut (ref mut block, _)) => block.statements().push(statement),
+                None => statements.push(statement),
+            }
+        }
+
+        // Every block needs a matching END
+        for (block, (start, end)) in blocks {
+            let (line_number, keyword) = match block {
+                OpenBlock::IF(line_number, _, _, _) => (line_number, "IF"),
+                OpenBlock::DEF(line_number, _, _) => (line_number, "DEF"),
+                OpenBlock::ON(line_number, _, _) => (line_number, "ON"),
+                OpenBlock::BROKEN(line_number, keyword, _) => (line_number, keyword),
+            };
+            diagnostics.push(Diagnostic::error(
+                line_number,
+                start,
+                end,
+                format!("{} without matching END", keyword),
+            ));
+        }
+
+        // Jumps have to land in the body they're in
+        closed_scopes.append(&mut scopes);
+        for scope in closed_scopes.iter() {
+            for &(line_number, token, ref target) in scope.jumps.iter() {
+                let (message, suggestion) = match *target {
+                    JumpTarget::LABEL(ref name) if scope.labels.contains(&name.as_str()) => continue,
+                    JumpTarget::LABEL(ref name)
+                        if closed_scopes.iter().any(|other| other.labels.contains(&name.as_str())) =>
+                    {
+                        (format!("Label {} is outside the {}, so JUMP can't reach it", name, scope.kind), None)
+                    }
+                    JumpTarget::LABEL(ref name) => (
+                        format!("Unknown label for JUMP: {}", name),
+                        suggest(&["JUMP", name.as_str()], Some(1), &scope.labels, &[]),
+                    ),
+                    // Jumping just past the last command goes to the end of the body
+                    JumpTarget::INDEX(index) if index <= scope.commands => continue,
+                    JumpTarget::INDEX(index) => (
+                        format!(
+                            "Jump target {} is outside the {}, which has {} commands",
+                            index, scope.kind, scope.commands
+                        ),
+                        None,
+                    ),
+                };
+                diagnostics.push(
+                    Diagnostic::error(line_number, token.start, token.end, message)
+                        .with_suggestion(suggestion),
+                );
+            }
+        }
+
+        let mut program = SwarmProgram::compile(statements, definitions, handlers);
+        program.source = s.to_string();
+
+        // If the command list size is exceeded, throw an error
+        if program.bytecode.len() > max_length {
+            let line_number = program.source_lines[max_length];
+            let (start, end) = line_span(s, line_number);
+            diagnostics.push(Diagnostic::error(
+                line_number,
+                start,
+                end,
+                "Program is too long: use fewer commands.".into(),
+            ));
+        }
 
-            // If the command list size is exceeded, throw an error
-            if (command_list.len() > MAX_NUM_COMMANDS) {
-                return Err(GenericError {
-                    description: "Program is too long: use fewer commands.".into(),
+        if !diagnostics.is_empty() {
+            diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.start));
+            return Err(CompileError::new(diagnostics));
+        }
+
+        // Return the compiled program
+        Ok(program)
+    }
+}
+
+/// Removes a # or // comment from the end of a line
+fn strip_comment(line: &str) -> &str {
+    let end = match (line.find('#'), line.find("//")) {
+        (Some(hash), Some(slashes)) => hash.min(slashes),
+        (Some(hash), None) => hash,
+        (None, Some(slashes)) => slashes,
+        (None, None) => line.len(),
+    };
+    &line[..end]
+}
+
+/// A word in a line of swarm code
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    /// The text of the word
+    text: &'a str,
+    /// Column of the first character
+    start: usize,
+    /// Column just past the last character
+    end: usize,
+}
+
+/// Splits a line into words, keeping track of the columns they're found at
+fn tokenize(line: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    // Byte offset and column of the start of the current word
+    let mut word_start: Option<(usize, usize)> = None;
+    // Add a trailing space so the last word is ended like the others
+    let chars = line.char_indices().chain(Some((line.len(), ' ')));
+    for (column, (offset, c)) in chars.enumerate() {
+        match (word_start, c.is_whitespace()) {
+            (Some((start_offset, start_column)), true) => {
+                tokens.push(Token {
+                    text: &line[start_offset..offset],
+                    start: start_column,
+                    end: column,
                 });
+                word_start = None;
             }
+            (None, false) => word_start = Some((offset, column)),
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// The columns covered by a non-empty list of tokens
+fn span(tokens: &[Token]) -> (usize, usize) {
+    (tokens[0].start, tokens[tokens.len() - 1].end)
+}
+
+/// The columns covered by the code on a line of source, ignoring comments
+/// Lines without code cover no columns
+pub fn line_span(source: &str, line: usize) -> (usize, usize) {
+    let tokens = tokenize(strip_comment(source.lines().nth(line.saturating_sub(1)).unwrap_or("")));
+    if tokens.is_empty() {
+        (0, 0)
+    } else {
+        span(&tokens)
+    }
+}
+
+/// Suggests a fix for an unrecognized word in a line, if a similar valid word exists
+fn suggest(
+    words: &[&str],
+    index: Option<usize>,
+    labels: &[&str],
+    procedures: &[&str],
+) -> Option<String> {
+    // Valid words for the position of the error
+    let candidates: Vec<&str> = match (index, words[0].to_uppercase().as_str()) {
+        (Some(0), _) => COMMAND_NAMES.to_vec(),
+        (Some(1), "IF") => SENSOR_NAMES.to_vec(),
+        (Some(1), "FORMATION") => FORMATION_NAMES.to_vec(),
+        (Some(1), "AIM") => TARGET_NAMES.to_vec(),
+        (Some(1), "ON") => EVENT_NAMES.to_vec(),
+        (Some(1), "JUMP") => labels.to_vec(),
+        (Some(1), "CALL") => procedures.to_vec(),
+        _ => return None,
+    };
+    let word = words[index.unwrap_or(0)];
+    // Labels and procedures are case sensitive, but everything else isn't
+    let compare = |candidate: &str| {
+        if index == Some(1) && (words[0].to_uppercase() == "JUMP" || words[0].to_uppercase() == "CALL") {
+            edit_distance(word, candidate)
+        } else {
+            edit_distance(&word.to_uppercase(), candidate)
+        }
+    };
+    match candidates.iter().min_by_key(|candidate| compare(candidate)) {
+        // Only suggest words that are close enough to be a typo
+        Some(candidate) if compare(candidate) <= 2 && compare(candidate) < word.len() => {
+            Some(format!("did you mean {}?", candidate))
         }
+        _ => None,
+    }
+}
 
-        // Return command list
-        Ok(SwarmProgram::new(command_list))
+/// Number of single character insertions, deletions and substitutions between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the current prefix of a to each prefix of b
+    let mut distances: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous_diagonal = distances[0];
+        distances[0] = i + 1;
+        for j in 0..b.len() {
+            let substitution = previous_diagonal + if a_char == b[j] { 0 } else { 1 };
+            previous_diagonal = distances[j + 1];
+            distances[j + 1] = substitution.min(distances[j] + 1).min(distances[j + 1] + 1);
+        }
+    }
+    distances[b.len()]
+}
+
+/// Checks whether a string can be used as a label
+/// Labels must start with a letter or underscore so they can't be confused with indices
+fn is_valid_label(label: &str) -> bool {
+    match label.chars().next() {
+        Some(first) if first.is_alphabetic() || first == '_' => {
+            label.chars().all(|c| c.is_alphanumeric() || c == '_')
+        }
+        _ => false,
     }
 }
 
@@ -235,19 +1648,21 @@ fn test_comlist_generator() {
     };
 
     // Check if all commands registered correctly
-    assert_eq!(command_list.commands[0], SwarmCommand::MOVE);
-    assert_eq!(command_list.commands[1], SwarmCommand::FIRE);
-    assert_eq!(command_list.commands[2], SwarmCommand::MOVE);
-    assert_eq!(command_list.commands[3], SwarmCommand::TURN(-30.0));
-    assert_eq!(command_list.commands[4], SwarmCommand::NOOP);
-    assert_eq!(command_list.commands[5], SwarmCommand::NOOP);
-    assert_eq!(command_list.commands[6], SwarmCommand::MOVE);
-    assert_eq!(command_list.commands[7], SwarmCommand::FIRE);
+    assert_eq!(command_list.bytecode[0], Instruction::COMMAND(SwarmCommand::MOVE));
+    assert_eq!(command_list.bytecode[1], Instruction::COMMAND(SwarmCommand::FIRE));
+    assert_eq!(command_list.bytecode[2], Instruction::COMMAND(SwarmCommand::MOVE));
+    assert_eq!(command_list.bytecode[3], Instruction::COMMAND(SwarmCommand::TURN(-30.0)));
+    assert_eq!(command_list.bytecode[4], Instruction::COMMAND(SwarmCommand::NOOP));
+    assert_eq!(command_list.bytecode[5], Instruction::COMMAND(SwarmCommand::NOOP));
+    assert_eq!(command_list.bytecode[6], Instruction::COMMAND(SwarmCommand::MOVE));
+    assert_eq!(command_list.bytecode[7], Instruction::COMMAND(SwarmCommand::FIRE));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use error::Severity;
+    use rand::{Rng, SeedableRng, XorShiftRng};
     #[test]
     #[should_panic]
     fn parse_swarm_command() {
@@ -259,4 +1674,453 @@ mod tests {
     fn parse_swarm_program() {
         let command: SwarmProgram = "test".parse().unwrap();
     }
+
+    #[test]
+    fn parse_labels() {
+        let program: SwarmProgram = "FORMATION SPREAD\nloop:\nMOVE\nFIRE\nJUMP loop\nJUMP 0"
+            .parse()
+            .unwrap();
+        assert_eq!(program.bytecode.len(), 5);
+        assert_eq!(program.bytecode[3], Instruction::JUMP(1));
+        assert_eq!(program.bytecode[4], Instruction::JUMP(0));
+    }
+
+    #[test]
+    fn parse_label_at_end() {
+        let program: SwarmProgram = "MOVE\nJUMP end\nFIRE\nend:".parse().unwrap();
+        assert_eq!(program.bytecode[1], Instruction::JUMP(0));
+    }
+
+    #[test]
+    fn parse_unknown_label() {
+        let error = "MOVE\nJUMP nowhere".parse::<SwarmProgram>().unwrap_err();
+        assert_eq!(error.diagnostics.len(), 1);
+        assert_eq!(error.diagnostics[0].line, 2);
+        assert_eq!((error.diagnostics[0].start, error.diagnostics[0].end), (5, 12));
+        assert_eq!(error.diagnostics[0].message, "Unknown label for JUMP: nowhere");
+    }
+
+    #[test]
+    fn parse_label_scopes() {
+        // Each body has its own labels, so the same name can be used in each
+        let program: SwarmProgram = "loop:\nMOVE\nJUMP loop\nDEF spin\nloop:\nTURN 10\nJUMP loop\nEND"
+            .parse()
+            .unwrap();
+        assert_eq!(program.bytecode[1], Instruction::JUMP(0));
+        assert_eq!(program.bytecode[3], Instruction::JUMP(2));
+        // But jumps can't reach labels in another body
+        let error = "loop:\nCALL spin\nDEF spin\nJUMP loop\nEND\nON HIT\nJUMP inner\nEND\nJUMP inner\nDEF other\ninner:\nMOVE\nEND"
+            .parse::<SwarmProgram>()
+            .unwrap_err();
+        let messages: Vec<(usize, &str)> = error
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (4, "Label loop is outside the procedure, so JUMP can't reach it"),
+                (7, "Label inner is outside the handler, so JUMP can't reach it"),
+                (9, "Label inner is outside the main body, so JUMP can't reach it"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_jump_index() {
+        // Indices count the commands written in the body, not the instructions they compile to
+        let program: SwarmProgram = "IF WALL_NEAR 10\nTURN 10\nELSE\nTURN -10\nEND\nMOVE\nJUMP 3\nDEF step\nFIRE\nJUMP 2\nEND"
+            .parse()
+            .unwrap();
+        assert_eq!(program.bytecode[4], Instruction::COMMAND(SwarmCommand::MOVE));
+        assert_eq!(program.bytecode[5], Instruction::JUMP(4));
+        // Jumping past the last command of a procedure returns from it
+        assert_eq!(program.bytecode[7], Instruction::JUMP(8));
+        assert_eq!(program.bytecode[8], Instruction::RET);
+    }
+
+    #[test]
+    fn parse_conditionals() {
+        let program: SwarmProgram = "IF ENEMY_NEAR 100\nFIRE\nELSE\nMOVE\nEND\nIF health_low 3\nFORMATION GATHER\nEND\nTURN 10"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.bytecode,
+            vec![
+                Instruction::JUMP_UNLESS(Condition::ENEMY_NEAR(100.0), 3),
+                Instruction::COMMAND(SwarmCommand::FIRE),
+                Instruction::JUMP(4),
+                Instruction::COMMAND(SwarmCommand::MOVE),
+                Instruction::JUMP_UNLESS(Condition::HEALTH_LOW(3), 6),
+                Instruction::COMMAND(SwarmCommand::FORMATION(Formation::GATHER, None)),
+                Instruction::COMMAND(SwarmCommand::TURN(10.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_registers() {
+        let program: SwarmProgram = "SET R0 3\nADD f1 heading\nMUL F3 -0.5\nCMP R0 R1\nIF LESS\nMOVE\nEND"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.bytecode[..4].to_vec(),
+            vec![
+                Instruction::COMMAND(SwarmCommand::SET(Register::INT(0), Operand::CONSTANT(3.0))),
+                Instruction::COMMAND(SwarmCommand::ADD(Register::FLOAT(1), Operand::HEADING)),
+                Instruction::COMMAND(SwarmCommand::MUL(Register::FLOAT(3), Operand::CONSTANT(-0.5))),
+                Instruction::COMMAND(SwarmCommand::CMP(Register::INT(0), Operand::REGISTER(Register::INT(1)))),
+            ]
+        );
+        assert_eq!(program.bytecode[4], Instruction::JUMP_UNLESS(Condition::LESS, 0));
+        assert!("SET R4 1".parse::<SwarmCommand>().is_err());
+        assert!("ADD 1 R0".parse::<SwarmCommand>().is_err());
+        assert!("SUB R0".parse::<SwarmCommand>().is_err());
+    }
+
+    #[test]
+    fn parse_aim() {
+        assert_eq!(
+            "AIM nearest".parse::<SwarmCommand>().unwrap(),
+            SwarmCommand::AIM(Target::NEAREST)
+        );
+        assert_eq!(
+            "AIM LEADER".parse::<SwarmCommand>().unwrap(),
+            SwarmCommand::AIM(Target::LEADER)
+        );
+        assert!("AIM".parse::<SwarmCommand>().is_err());
+        assert!("AIM SOMEONE".parse::<SwarmCommand>().is_err());
+    }
+
+    #[test]
+    fn parse_formations() {
+        assert_eq!(
+            "FORMATION wedge".parse::<SwarmCommand>().unwrap(),
+            SwarmCommand::FORMATION(Formation::WEDGE, None)
+        );
+        assert_eq!(
+            "FORMATION BOX 12.5".parse::<SwarmCommand>().unwrap(),
+            SwarmCommand::FORMATION(Formation::BOX, Some(12.5))
+        );
+        assert!("FORMATION LINE 0".parse::<SwarmCommand>().is_err());
+        assert!("FORMATION LINE 500".parse::<SwarmCommand>().is_err());
+        assert!("FORMATION LINE 10 10".parse::<SwarmCommand>().is_err());
+    }
+
+    #[test]
+    fn parse_diagnostics() {
+        let error = "MOVE\n  MOOVE\nTURN 45\n\nFORMATION GATHR\nJUMP 9"
+            .parse::<SwarmProgram>()
+            .unwrap_err();
+        let diagnostics = error.diagnostics;
+        assert_eq!(diagnostics.len(), 4);
+        // Unknown command, with the columns of the word
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!((diagnostics[0].start, diagnostics[0].end), (2, 7));
+        assert_eq!(diagnostics[0].severity, Severity::ERROR);
+        assert_eq!(diagnostics[0].suggestion, Some("did you mean MOVE?".into()));
+        // Bad argument points at the argument
+        assert_eq!(diagnostics[1].line, 3);
+        assert_eq!((diagnostics[1].start, diagnostics[1].end), (5, 7));
+        assert_eq!(diagnostics[2].line, 5);
+        assert_eq!(diagnostics[2].suggestion, Some("did you mean GATHER?".into()));
+        assert_eq!(diagnostics[3].line, 6);
+    }
+
+    #[test]
+    fn parse_comments() {
+        let program: SwarmProgram = "# Circle strafe\n\nstart: // the main loop\nMOVE # forward\n  // turn a bit\nTURN 10\nIF ENEMY_NEAR 50\nFIRE\nELSE\nNOOP\nEND\nJUMP start"
+            .parse()
+            .unwrap();
+        assert_eq!(program.bytecode.len(), 7);
+        assert_eq!(program.bytecode[0], Instruction::COMMAND(SwarmCommand::MOVE));
+        assert_eq!(program.source_lines, vec![4, 6, 7, 8, 9, 10, 12]);
+        assert_eq!(program.current_line(), Some(4));
+        // Errors still point at the right column with comments around
+        let error = "MOVE\nTURN 99 # too far".parse::<SwarmProgram>().unwrap_err();
+        assert_eq!(error.diagnostics[0].line, 2);
+        assert_eq!((error.diagnostics[0].start, error.diagnostics[0].end), (5, 7));
+    }
+
+    #[test]
+    fn parse_procedures() {
+        let program: SwarmProgram = "DEF dodge\nTURN 30\nIF BULLET_NEAR 40\nRET\nEND\nTURN -30\nEND\nloop:\nCALL dodge\nFIRE\nJUMP loop"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.bytecode,
+            vec![
+                Instruction::CALL(3),
+                Instruction::COMMAND(SwarmCommand::FIRE),
+                Instruction::JUMP(0),
+                Instruction::COMMAND(SwarmCommand::TURN(30.0)),
+                Instruction::JUMP_UNLESS(Condition::BULLET_NEAR(40.0), 6),
+                Instruction::RET,
+                Instruction::COMMAND(SwarmCommand::TURN(-30.0)),
+                Instruction::RET,
+            ]
+        );
+        assert_eq!(program.main_length, 3);
+        assert_eq!(program.source_lines, vec![9, 10, 11, 2, 3, 4, 6, 7]);
+    }
+
+    #[test]
+    fn parse_bad_procedures() {
+        assert!("RET".parse::<SwarmProgram>().is_err());
+        assert!("CALL nothing".parse::<SwarmProgram>().is_err());
+        assert!("DEF a\nMOVE".parse::<SwarmProgram>().is_err());
+        assert!("IF WALL_NEAR 5\nDEF a\nEND\nEND".parse::<SwarmProgram>().is_err());
+        assert!("DEF a\nEND\nDEF a\nEND".parse::<SwarmProgram>().is_err());
+    }
+
+    #[test]
+    fn parse_handlers() {
+        let program: SwarmProgram = "MOVE\nON HIT\nTURN 10\nRET\nEND\non wall\nTURN -10\nEND"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.bytecode,
+            vec![
+                Instruction::COMMAND(SwarmCommand::MOVE),
+                Instruction::COMMAND(SwarmCommand::TURN(10.0)),
+                Instruction::RET,
+                Instruction::RET,
+                Instruction::COMMAND(SwarmCommand::TURN(-10.0)),
+                Instruction::RET,
+            ]
+        );
+        assert_eq!(program.main_length, 1);
+        assert_eq!(program.handler_entries, vec![(Event::HIT, 1), (Event::WALL, 4)]);
+        assert!("ON HIT\nEND\nON HIT\nEND".parse::<SwarmProgram>().is_err());
+        assert!("ON SOMETHING\nEND".parse::<SwarmProgram>().is_err());
+        assert!("IF WALL_NEAR 5\nON KILL\nEND\nEND".parse::<SwarmProgram>().is_err());
+        assert!("ON MEMBER_LOST\nMOVE".parse::<SwarmProgram>().is_err());
+    }
+
+    #[test]
+    fn parse_bad_headers() {
+        // A mistake in the first line of a block doesn't unbalance the rest of the program
+        let error = "IF ENEMY_NEARR 100\nFIRE\nELSE\nMOVE\nEND".parse::<SwarmProgram>().unwrap_err();
+        assert_eq!(error.diagnostics.len(), 1);
+        assert_eq!(error.diagnostics[0].line, 1);
+        assert_eq!(error.diagnostics[0].suggestion, Some("did you mean ENEMY_NEAR?".into()));
+        // Or make every call to a procedure unknown
+        let error = "CALL dodge\nDEF dodge now\nTURN 10\nRET\nEND".parse::<SwarmProgram>().unwrap_err();
+        assert_eq!(error.diagnostics.len(), 1);
+        assert_eq!(error.diagnostics[0].line, 2);
+        let error = "ON HIT\nTURN 10\nEND\nON HIT\nRET\nEND".parse::<SwarmProgram>().unwrap_err();
+        assert_eq!(error.diagnostics.len(), 1);
+        assert_eq!(error.diagnostics[0].message, "Duplicate handler: HIT");
+    }
+
+    #[test]
+    fn parse_unbalanced_blocks() {
+        assert!("IF WALL_NEAR 10\nMOVE".parse::<SwarmProgram>().is_err());
+        assert!("MOVE\nEND".parse::<SwarmProgram>().is_err());
+        assert!("IF WALL_NEAR 10\nELSE\nELSE\nEND".parse::<SwarmProgram>().is_err());
+        assert!("IF SOMETHING 10\nMOVE\nEND".parse::<SwarmProgram>().is_err());
+    }
+
+    #[test]
+    fn parse_jump_out_of_bounds() {
+        let error = "MOVE\nJUMP 5\nDEF step\nFIRE\nJUMP 3\nEND".parse::<SwarmProgram>().unwrap_err();
+        assert_eq!(error.diagnostics.len(), 2);
+        assert_eq!(error.diagnostics[0].line, 2);
+        assert_eq!((error.diagnostics[0].start, error.diagnostics[0].end), (5, 6));
+        assert_eq!(
+            error.diagnostics[0].message,
+            "Jump target 5 is outside the main body, which has 2 commands"
+        );
+        // Procedures can't jump into the main body by index either
+        assert_eq!(error.diagnostics[1].line, 5);
+        assert_eq!(
+            error.diagnostics[1].message,
+            "Jump target 3 is outside the procedure, which has 2 commands"
+        );
+    }
+    #[test]
+    fn print_program() {
+        let program: SwarmProgram = "call dodge # first\nloop:\n  if enemy_near 50.5\nfire\nelse\nset r1 heading\nend\njump loop\ndef dodge\nturn -7.25\nret\nend"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            program.to_string(),
+            "CALL dodge\nloop:\nIF ENEMY_NEAR 50.5\n    FIRE\nELSE\n    SET R1 HEADING\nEND\nJUMP loop\nDEF dodge\n    TURN -7.25\n    RET\nEND\n"
+        );
+    }
+
+    /// Picks a random element of a non-empty slice
+    fn pick<'a, T>(rng: &mut XorShiftRng, items: &'a [T]) -> &'a T {
+        rng.choose(items).unwrap()
+    }
+
+    /// Generates a random command that the parser accepts
+    fn random_command(rng: &mut XorShiftRng) -> SwarmCommand {
+        let register = if rng.gen() {
+            Register::INT(rng.gen_range(0, NUM_REGISTERS))
+        } else {
+            Register::FLOAT(rng.gen_range(0, NUM_REGISTERS))
+        };
+        let operand = match rng.gen_range(0, 4) {
+            0 => Operand::REGISTER(Register::FLOAT(1)),
+            1 => Operand::CONSTANT(rng.gen_range(-1000.0, 1000.0)),
+            2 => Operand::HEADING,
+            _ => Operand::MEMBERS,
+        };
+        // TURN 0 isn't accepted, so keep away from it
+        let turn_amt = rng.gen_range(0.1, MAX_TURN) * if rng.gen() { -1.0 } else { 1.0 };
+        let formation = *pick(
+            rng,
+            &[
+                Formation::GATHER,
+                Formation::SPREAD,
+                Formation::LINE,
+                Formation::COLUMN,
+                Formation::WEDGE,
+                Formation::CIRCLE,
+                Formation::BOX,
+            ],
+        );
+        let spacing = if rng.gen() {
+            Some(rng.gen_range(1.0, MAX_SPACING))
+        } else {
+            None
+        };
+        let target = *pick(rng, &[Target::NEAREST, Target::WEAKEST, Target::LEADER]);
+        *pick(
+            rng,
+            &[
+                SwarmCommand::MOVE,
+                SwarmCommand::LEFT,
+                SwarmCommand::RIGHT,
+                SwarmCommand::UP,
+                SwarmCommand::DOWN,
+                SwarmCommand::FIRE,
+                SwarmCommand::TURN(turn_amt),
+                SwarmCommand::NOOP,
+                SwarmCommand::FORMATION(formation, spacing),
+                SwarmCommand::SET(register, operand),
+                SwarmCommand::ADD(register, operand),
+                SwarmCommand::SUB(register, operand),
+                SwarmCommand::MUL(register, operand),
+                SwarmCommand::CMP(register, operand),
+                SwarmCommand::AIM(target),
+            ],
+        )
+    }
+
+    /// Generates a random condition that the parser accepts
+    fn random_condition(rng: &mut XorShiftRng) -> Condition {
+        let distance = rng.gen_range(0.0, 500.0);
+        let num_members = rng.gen_range(0, 20);
+        *pick(
+            rng,
+            &[
+                Condition::ENEMY_NEAR(distance),
+                Condition::BULLET_NEAR(distance),
+                Condition::HEALTH_LOW(num_members),
+                Condition::WALL_NEAR(distance),
+                Condition::LESS,
+                Condition::EQUAL,
+                Condition::GREATER,
+            ],
+        )
+    }
+
+    /// Generates random statements
+    /// Labels are taken from the list so each is only defined once
+    fn random_statements(
+        rng: &mut XorShiftRng,
+        depth: usize,
+        in_procedure: bool,
+        labels: &mut Vec<String>,
+        all_labels: &[String],
+        procedures: &[String],
+    ) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        for _ in 0..rng.gen_range(0, 4) {
+            let statement = match rng.gen_range(0, 8) {
+                0 if !labels.is_empty() => Statement::LABEL(0, labels.pop().unwrap()),
+                1 => Statement::JUMP(0, JumpTarget::LABEL(pick(rng, all_labels).clone())),
+                2 => Statement::JUMP(0, JumpTarget::INDEX(0)),
+                3 if !procedures.is_empty() => Statement::CALL(0, pick(rng, procedures).clone()),
+                4 if in_procedure => Statement::RET(0),
+                5 if depth < 2 => {
+                    let condition = random_condition(rng);
+                    let then = random_statements(rng, depth + 1, in_procedure, labels, all_labels, procedures);
+                    let otherwise = if rng.gen() {
+                        Some((0, random_statements(rng, depth + 1, in_procedure, labels, all_labels, procedures)))
+                    } else {
+                        None
+                    };
+                    Statement::IF(0, condition, then, otherwise)
+                }
+                _ => Statement::COMMAND(0, random_command(rng)),
+            };
+            statements.push(statement);
+        }
+        statements
+    }
+
+    /// Generates the statements of the main body, a procedure or a handler
+    /// Each body defines every label, since jumps can't leave the body they're in
+    fn random_body(
+        rng: &mut XorShiftRng,
+        in_procedure: bool,
+        all_labels: &[String],
+        procedures: &[String],
+    ) -> Vec<Statement> {
+        let mut labels = all_labels.to_vec();
+        let mut statements = random_statements(rng, 0, in_procedure, &mut labels, all_labels, procedures);
+        // Every label that is jumped to has to exist
+        statements.extend(labels.drain(..).map(|label| Statement::LABEL(0, label)));
+        statements
+    }
+
+    #[test]
+    fn print_round_trip() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([7, 11, 13, 17]);
+        let all_labels: Vec<String> = vec!["start".into(), "middle".into(), "_end2".into()];
+        let mut checked = 0;
+        while checked < 500 {
+            let names: Vec<String> = (0..rng.gen_range(0, 3)).map(|i| format!("p{}", i)).collect();
+            let mut procedures = Vec::new();
+            for name in names.iter() {
+                procedures.push(Procedure {
+                    line: 0,
+                    name: name.clone(),
+                    body: random_body(&mut rng, true, &all_labels, &names),
+                    end_line: 0,
+                });
+            }
+            let mut handlers = Vec::new();
+            for &event in [Event::HIT, Event::MEMBER_LOST, Event::WALL, Event::KILL].iter() {
+                if rng.gen_weighted_bool(4) {
+                    handlers.push(Handler {
+                        line: 0,
+                        event: event,
+                        body: random_body(&mut rng, true, &all_labels, &names),
+                        end_line: 0,
+                    });
+                }
+            }
+            let statements = random_body(&mut rng, false, &all_labels, &names);
+            let program = SwarmProgram::compile(statements, procedures, handlers);
+            // Only programs that fit can be parsed
+            if program.bytecode.len() > MAX_NUM_COMMANDS {
+                continue;
+            }
+            let source = program.to_string();
+            match source.parse::<SwarmProgram>() {
+                Ok(parsed) => {
+                    assert_eq!(parsed, program, "\n{}", source);
+                    // Printing is canonical, so printing again changes nothing
+                    assert_eq!(parsed.to_string(), source);
+                }
+                Err(error) => panic!("{}\n{}", error, source),
+            }
+            checked += 1;
+        }
+    }
 }
@@ -0,0 +1,296 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+use config::Rules;
+use simulation::Simulation;
+use swarm_language::SwarmProgram;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Rating every program starts the tournament with
+pub const INITIAL_RATING: f64 = 1500.0;
+/// Most rating a program can gain or lose in one game
+const K_FACTOR: f64 = 32.0;
+
+/// Settings for a round-robin tournament between programs
+#[derive(Clone, Debug)]
+pub struct Tournament {
+    /// The width of the world
+    pub width: f32,
+    /// The height of the world
+    pub height: f32,
+    /// Number of ticks each game lasts
+    pub ticks: u64,
+    /// Every group of programs plays once with each seed
+    pub seeds: Vec<u64>,
+    /// Number of programs in each game
+    /// 2 plays every pairing, and larger groups play free-for-alls
+    pub group_size: usize,
+    /// The balance of the game
+    pub rules: Rules,
+}
+
+/// Functions for a tournament
+impl Tournament {
+    /// Constructor
+    /// Plays every pairing with a single seed
+    pub fn new(width: f32, height: f32, ticks: u64) -> Self {
+        Tournament {
+            width: width,
+            height: height,
+            ticks: ticks,
+            seeds: vec![0],
+            group_size: 2,
+            rules: Rules::default(),
+        }
+    }
+    /// Plays every group of programs with every seed
+    /// Group sizes larger than the number of programs play everyone at once
+    pub fn run(&self, programs: &[(String, SwarmProgram)]) -> TournamentResult {
+        let mut standings: Vec<Standing> = programs
+            .iter()
+            .map(|&(ref name, _)| Standing::new(name.clone()))
+            .collect();
+        let group_size = self.group_size.min(programs.len()).max(2);
+        for group in groups(programs.len(), group_size) {
+            for (round, &seed) in self.seeds.iter().enumerate() {
+                let order = seats(&group, round);
+                let entrants: Vec<(String, SwarmProgram)> =
+                    order.iter().map(|&index| programs[index].clone()).collect();
+                let result = Simulation::new(self.width, self.height, self.ticks, seed)
+                    .with_rules(self.rules)
+                    .run(&entrants);
+                // Each program's standing index and score, from 1st place to last
+                let placings: Vec<(usize, (i64, u64))> = result
+                    .leaderboard
+                    .iter()
+                    .map(|program| {
+                        (order[program.player], (program.experience, program.survival_time))
+                    })
+                    .collect();
+                let best = placings[0].1;
+                let tied_first = placings.iter().filter(|&&(_, score)| score == best).count();
+                for &(index, score) in placings.iter() {
+                    let standing = &mut standings[index];
+                    standing.games += 1;
+                    if score != best {
+                        standing.losses += 1;
+                    } else if tied_first == 1 {
+                        standing.wins += 1;
+                    } else {
+                        standing.draws += 1;
+                    }
+                }
+                // Each pair of programs in the game counts as a game between the two
+                // Changes are based on the ratings from before the game
+                let mut changes: Vec<f64> = vec![0.0; placings.len()];
+                for a in 0..placings.len() {
+                    for b in (a + 1)..placings.len() {
+                        let (winner, loser) = (placings[a], placings[b]);
+                        let actual = if winner.1 == loser.1 { 0.5 } else { 1.0 };
+                        let difference = standings[loser.0].rating - standings[winner.0].rating;
+                        let expected = 1.0 / (1.0 + 10.0f64.powf(difference / 400.0));
+                        let change = K_FACTOR * (actual - expected) / (placings.len() - 1) as f64;
+                        changes[a] += change;
+                        changes[b] -= change;
+                    }
+                }
+                for (&(index, _), change) in placings.iter().zip(changes) {
+                    standings[index].rating += change;
+                }
+            }
+        }
+        for standing in standings.iter_mut() {
+            if standing.games > 0 {
+                standing.win_rate = standing.wins as f64 / standing.games as f64;
+            }
+        }
+        // Best rating first
+        standings.sort_by(|a, b| {
+            b.rating
+                .partial_cmp(&a.rating)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        TournamentResult {
+            seeds: self.seeds.clone(),
+            group_size: group_size,
+            standings: standings,
+        }
+    }
+}
+
+/// The order a group of programs plays in for a round, which is their player IDs
+/// The first player spawns and updates first, so each program takes a turn at each seat
+fn seats(group: &[usize], round: usize) -> Vec<usize> {
+    (0..group.len())
+        .map(|seat| group[(seat + round) % group.len()])
+        .collect()
+}
+
+/// Every group of `size` indices out of `count`, in lexicographic order
+fn groups(count: usize, size: usize) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    if size == 0 || size > count {
+        return groups;
+    }
+    let mut group: Vec<usize> = (0..size).collect();
+    loop {
+        groups.push(group.clone());
+        // Find the last index that can still be increased
+        let mut i = size;
+        while i > 0 && group[i - 1] == count - size + i - 1 {
+            i -= 1;
+        }
+        if i == 0 {
+            return groups;
+        }
+        group[i - 1] += 1;
+        for j in i..size {
+            group[j] = group[j - 1] + 1;
+        }
+    }
+}
+
+/// The outcome of a tournament
+#[derive(Clone, Debug, Serialize)]
+pub struct TournamentResult {
+    /// Seeds every group played with
+    pub seeds: Vec<u64>,
+    /// Number of programs in each game
+    pub group_size: usize,
+    /// How each program did, from best rating to worst
+    pub standings: Vec<Standing>,
+}
+
+/// How one program did over a tournament
+#[derive(Clone, Debug, Serialize)]
+pub struct Standing {
+    /// Name of the program
+    pub name: String,
+    /// Elo rating after every game
+    pub rating: f64,
+    /// Number of games played
+    pub games: usize,
+    /// Games finished in 1st place alone
+    pub wins: usize,
+    /// Games finished tied for 1st place
+    pub draws: usize,
+    /// Games finished below 1st place
+    pub losses: usize,
+    /// Fraction of games won
+    pub win_rate: f64,
+}
+
+/// Functions for a standing
+impl Standing {
+    /// Constructor for a program that hasn't played yet
+    fn new(name: String) -> Self {
+        Standing {
+            name: name,
+            rating: INITIAL_RATING,
+            games: 0,
+            wins: 0,
+            draws: 0,
+            losses: 0,
+            win_rate: 0.0,
+        }
+    }
+}
+
+/// Allows TournamentResult to be printed
+impl fmt::Display for TournamentResult {
+    /// Writes the standings as a table
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.standings
+            .iter()
+            .map(|standing| standing.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("Program".len());
+        write!(
+            formatter,
+            "{:>4}  {:<width$}  {:>6}  {:>5}  {:>4}  {:>5}  {:>6}  {:>8}",
+            "Rank",
+            "Program",
+            "Rating",
+            "Games",
+            "Wins",
+            "Draws",
+            "Losses",
+            "Win rate",
+            width = width
+        )?;
+        for (rank, standing) in self.standings.iter().enumerate() {
+            write!(
+                formatter,
+                "\n{:>4}  {:<width$}  {:>6.0}  {:>5}  {:>4}  {:>5}  {:>6}  {:>7.1}%",
+                rank + 1,
+                standing.name,
+                standing.rating,
+                standing.games,
+                standing.wins,
+                standing.draws,
+                standing.losses,
+                standing.win_rate * 100.0,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups() {
+        assert_eq!(groups(3, 2), vec![vec![0, 1], vec![0, 2], vec![1, 2]]);
+        assert_eq!(groups(4, 3).len(), 4);
+        assert_eq!(groups(3, 3), vec![vec![0, 1, 2]]);
+        assert!(groups(1, 2).is_empty());
+        // Programs take turns going first
+        assert_eq!(seats(&[0, 2], 0), vec![0, 2]);
+        assert_eq!(seats(&[0, 2], 1), vec![2, 0]);
+        assert_eq!(seats(&[1, 2, 3], 4), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn test_tournament() {
+        let programs = vec![
+            ("hunter".to_string(), "AIM NEAREST\nMOVE\nFIRE".parse().unwrap()),
+            ("sitter".to_string(), "NOOP".parse().unwrap()),
+            ("spinner".to_string(), "MOVE\nTURN 10".parse().unwrap()),
+        ];
+        let mut tournament = Tournament::new(400.0, 400.0, 600);
+        tournament.seeds = vec![1, 2];
+        let result = tournament.run(&programs);
+        assert_eq!(result.standings[0].name, "hunter");
+        for standing in result.standings.iter() {
+            // Two opponents, two seeds each
+            assert_eq!(standing.games, 4);
+            assert_eq!(standing.wins + standing.draws + standing.losses, 4);
+        }
+        // Elo only moves rating between programs
+        let total: f64 = result.standings.iter().map(|standing| standing.rating).sum();
+        assert!((total - 3.0 * INITIAL_RATING).abs() < 0.001);
+
+        // Free-for-alls put everyone in one game
+        tournament.group_size = 3;
+        let result = tournament.run(&programs);
+        assert!(result.standings.iter().all(|standing| standing.games == 2));
+    }
+}
@@ -0,0 +1,411 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+use swarm_language::{Condition, Event, Handler, JumpTarget, Operand, Procedure, RuntimeFault,
+                     Statement, SwarmCommand, SwarmProgram, MAX_CALL_DEPTH};
+use std::cmp::Ordering;
+
+/// A single instruction of a compiled swarm program
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Instruction {
+    /// Run a command
+    COMMAND(SwarmCommand),
+    /// Continue at the instruction with the given index
+    JUMP(usize),
+    /// Continue at the given index unless the condition holds
+    JUMP_UNLESS(Condition, usize),
+    /// Push the index of the next instruction and continue at the given index
+    CALL(usize),
+    /// Continue at the index on top of the call stack
+    RET,
+}
+
+/// Functions for Instruction
+impl Instruction {
+    /// Number of cycles the instruction uses from a swarm's per-tick budget
+    pub fn cost(&self) -> usize {
+        match *self {
+            Instruction::COMMAND(command) => command.cost(),
+            // Conditions may have to look at the whole world
+            Instruction::JUMP_UNLESS(_, _) | Instruction::CALL(_) => 2,
+            Instruction::JUMP(_) | Instruction::RET => 1,
+        }
+    }
+}
+
+/// Information about the world that a running program can ask for
+pub trait Sensors {
+    /// Checks a condition about the world
+    /// Comparisons are handled by the VM
+    fn check(&self, condition: Condition) -> bool;
+    /// Reads the value of an operand from the world
+    /// Registers and constants are handled by the VM
+    fn read(&self, operand: Operand) -> f64;
+}
+
+/// Jump that still needs its target filled in
+enum Fixup<'a> {
+    /// Jump to a label
+    LABEL(&'a str),
+    /// Jump to a command by its index in the body
+    INDEX(usize),
+    /// Call of a procedure
+    CALL(&'a str),
+}
+
+/// Instructions being built by compile
+struct Builder<'a> {
+    /// The compiled instructions
+    bytecode: Vec<Instruction>,
+    /// Line each instruction was compiled from
+    source_lines: Vec<usize>,
+    /// Labels in the body being compiled and the index they point at
+    labels: Vec<(&'a str, usize)>,
+    /// Index of the first instruction of each command in the body being compiled
+    commands: Vec<usize>,
+    /// Jumps to labels and procedures by index
+    fixups: Vec<(usize, Fixup<'a>)>,
+}
+
+/// Functions for Builder
+impl<'a> Builder<'a> {
+    /// Adds an instruction, returning its index
+    fn push(&mut self, line: usize, instruction: Instruction) -> usize {
+        self.bytecode.push(instruction);
+        self.source_lines.push(line);
+        self.bytecode.len() - 1
+    }
+    /// Replaces the target of a jump
+    fn patch(&mut self, index: usize, target: usize) {
+        self.bytecode[index] = match self.bytecode[index] {
+            Instruction::JUMP(_) => Instruction::JUMP(target),
+            Instruction::JUMP_UNLESS(condition, _) => Instruction::JUMP_UNLESS(condition, target),
+            Instruction::CALL(_) => Instruction::CALL(target),
+            instruction => instruction,
+        };
+    }
+    /// Compiles the main body, a procedure or a handler
+    /// Jumps can only reach labels and commands in the same body
+    fn body(&mut self, statements: &'a [Statement]) {
+        let start = self.bytecode.len();
+        self.statements(statements);
+        let end = self.bytecode.len();
+        let labels: Vec<(&str, usize)> = self.labels.drain(..).collect();
+        let commands: Vec<usize> = self.commands.drain(..).collect();
+        let fixups: Vec<(usize, Fixup)> = self.fixups.drain(..).collect();
+        for (index, fixup) in fixups {
+            // Unknown labels are reported by the parser, so they just go to the start
+            let target = match fixup {
+                Fixup::LABEL(name) => labels
+                    .iter()
+                    .find(|&&(label, _)| label == name)
+                    .map_or(start, |&(_, target)| target),
+                // Jumping past the last command goes to the end of the body
+                Fixup::INDEX(command) => commands.get(command).cloned().unwrap_or(end),
+                // Calls are filled in once every procedure has an index
+                Fixup::CALL(_) => {
+                    self.fixups.push((index, fixup));
+                    continue;
+                }
+            };
+            self.patch(index, target);
+        }
+    }
+    /// Compiles a list of statements
+    fn statements(&mut self, statements: &'a [Statement]) {
+        for statement in statements {
+            // Jumps to an index count every statement but labels
+            match *statement {
+                Statement::LABEL(_, _) => {}
+                _ => self.commands.push(self.bytecode.len()),
+            }
+            match *statement {
+                Statement::COMMAND(line, command) => {
+                    self.push(line, Instruction::COMMAND(command));
+                }
+                Statement::LABEL(_, ref name) => {
+                    let index = self.bytecode.len();
+                    self.labels.push((name, index));
+                }
+                Statement::JUMP(line, JumpTarget::INDEX(command)) => {
+                    let index = self.push(line, Instruction::JUMP(0));
+                    self.fixups.push((index, Fixup::INDEX(command)));
+                }
+                Statement::JUMP(line, JumpTarget::LABEL(ref name)) => {
+                    let index = self.push(line, Instruction::JUMP(0));
+                    self.fixups.push((index, Fixup::LABEL(name)));
+                }
+                Statement::CALL(line, ref name) => {
+                    let index = self.push(line, Instruction::CALL(0));
+                    self.fixups.push((index, Fixup::CALL(name)));
+                }
+                Statement::RET(line) => {
+                    self.push(line, Instruction::RET);
+                }
+                Statement::IF(line, condition, ref then, ref otherwise) => {
+                    let branch = self.push(line, Instruction::JUMP_UNLESS(condition, 0));
+                    self.statements(then);
+                    match *otherwise {
+                        Some((else_line, ref otherwise)) => {
+                            // Skip over the ELSE statements at the end of the IF statements
+                            let skip = self.push(else_line, Instruction::JUMP(0));
+                            let start = self.bytecode.len();
+                            self.patch(branch, start);
+                            self.statements(otherwise);
+                            let end = self.bytecode.len();
+                            self.patch(skip, end);
+                        }
+                        None => {
+                            let end = self.bytecode.len();
+                            self.patch(branch, end);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Compiles statements, procedures and handlers to bytecode
+/// Returns the instructions, the line each came from, the length of the main body,
+/// and where each handler starts
+/// Procedures and then handlers are placed after the main body, each ending with a RET
+pub fn compile(
+    statements: &[Statement],
+    procedures: &[Procedure],
+    handlers: &[Handler],
+) -> (Vec<Instruction>, Vec<usize>, usize, Vec<(Event, usize)>) {
+    let mut builder = Builder {
+        bytecode: Vec::new(),
+        source_lines: Vec::new(),
+        labels: Vec::new(),
+        commands: Vec::new(),
+        fixups: Vec::new(),
+    };
+    builder.body(statements);
+    let main_length = builder.bytecode.len();
+
+    // Where each procedure starts
+    let mut entries: Vec<(&str, usize)> = Vec::new();
+    for procedure in procedures {
+        entries.push((&procedure.name, builder.bytecode.len()));
+        builder.body(&procedure.body);
+        builder.push(procedure.end_line, Instruction::RET);
+    }
+    // Handlers return to wherever the program was interrupted
+    let mut handler_entries: Vec<(Event, usize)> = Vec::new();
+    for handler in handlers {
+        handler_entries.push((handler.event, builder.bytecode.len()));
+        builder.body(&handler.body);
+        builder.push(handler.end_line, Instruction::RET);
+    }
+
+    // Fill in the calls now that every procedure has an index
+    // Unknown names are reported by the parser, so they just go to the start
+    let fixups: Vec<(usize, Fixup)> = builder.fixups.drain(..).collect();
+    for (index, fixup) in fixups {
+        let target = match fixup {
+            Fixup::CALL(name) => entries.iter().find(|&&(entry, _)| entry == name),
+            _ => None,
+        };
+        builder.patch(index, target.map(|&(_, target)| target).unwrap_or(0));
+    }
+
+    // Jumping to the end of the main body loops back to the start
+    for index in 0..main_length {
+        match builder.bytecode[index] {
+            Instruction::JUMP(target) | Instruction::JUMP_UNLESS(_, target)
+                if target == main_length =>
+            {
+                builder.patch(index, 0)
+            }
+            _ => {}
+        }
+    }
+
+    (builder.bytecode, builder.source_lines, main_length, handler_entries)
+}
+
+/// Checks a condition, using the program for comparisons
+fn check<S: Sensors>(program: &SwarmProgram, sensors: &S, condition: Condition) -> bool {
+    match condition {
+        Condition::LESS => program.comparison == Some(Ordering::Less),
+        Condition::EQUAL => program.comparison == Some(Ordering::Equal),
+        Condition::GREATER => program.comparison == Some(Ordering::Greater),
+        _ => sensors.check(condition),
+    }
+}
+
+/// Reads an operand, using the program for registers and constants
+fn read<S: Sensors>(program: &SwarmProgram, sensors: &S, operand: Operand) -> f64 {
+    match operand {
+        Operand::REGISTER(register) => program.get(register),
+        Operand::CONSTANT(value) => value,
+        _ => sensors.read(operand),
+    }
+}
+
+/// Runs a program until it reaches a command that acts on the world
+/// Instructions are run until their costs would go over budget cycles
+/// An instruction that doesn't fit waits for the next call
+/// Returns the command to act on, if one was reached
+pub fn run<S: Sensors>(
+    program: &mut SwarmProgram,
+    sensors: &S,
+    budget: usize,
+) -> Option<SwarmCommand> {
+    // Cycles used so far
+    let mut cycles = 0;
+    loop {
+        // Stopped, empty and idle programs do nothing
+        if program.fault.is_some() || program.bytecode.is_empty() || program.is_idle() {
+            return None;
+        }
+        let instruction = program.bytecode[program.program_counter];
+        // The first instruction always runs, so expensive ones can't stall the program
+        if cycles > 0 && cycles + instruction.cost() > budget {
+            return None;
+        }
+        cycles += instruction.cost();
+        match instruction {
+            Instruction::COMMAND(command) => {
+                let next = program.next_counter();
+                if command.ends_tick() {
+                    program.program_counter = next;
+                    return Some(command);
+                }
+                match command {
+                    SwarmCommand::SET(register, operand) => {
+                        let value = read(program, sensors, operand);
+                        program.set(register, value);
+                    }
+                    SwarmCommand::ADD(register, operand) => {
+                        let value = program.get(register) + read(program, sensors, operand);
+                        program.set(register, value);
+                    }
+                    SwarmCommand::SUB(register, operand) => {
+                        let value = program.get(register) - read(program, sensors, operand);
+                        program.set(register, value);
+                    }
+                    SwarmCommand::MUL(register, operand) => {
+                        let value = program.get(register) * read(program, sensors, operand);
+                        program.set(register, value);
+                    }
+                    SwarmCommand::CMP(register, operand) => {
+                        let value = read(program, sensors, operand);
+                        program.comparison = program.get(register).partial_cmp(&value);
+                    }
+                    _ => {}
+                }
+                program.program_counter = next;
+            }
+            Instruction::JUMP(target) => program.program_counter = target,
+            Instruction::JUMP_UNLESS(condition, target) => {
+                program.program_counter = if check(program, sensors, condition) {
+                    program.next_counter()
+                } else {
+                    target
+                };
+            }
+            Instruction::CALL(target) => {
+                if program.call_stack.len() >= MAX_CALL_DEPTH {
+                    program.fault = Some(RuntimeFault::STACK_OVERFLOW);
+                } else {
+                    let next = program.next_counter();
+                    program.call_stack.push(next);
+                    program.program_counter = target;
+                }
+            }
+            Instruction::RET => match program.call_stack.pop() {
+                Some(target) => {
+                    program.program_counter = target;
+                    // Leaving a handler lets events interrupt the program again
+                    if program.handler_depth == Some(program.call_stack.len()) {
+                        program.handler_depth = None;
+                    }
+                }
+                None => program.fault = Some(RuntimeFault::STACK_UNDERFLOW),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use swarm_language::Register;
+
+    /// Sensors for a swarm alone in an empty world
+    struct Empty;
+
+    impl Sensors for Empty {
+        fn check(&self, _condition: Condition) -> bool {
+            false
+        }
+        fn read(&self, _operand: Operand) -> f64 {
+            0.0
+        }
+    }
+
+    #[test]
+    fn run_budget() {
+        let mut program: SwarmProgram = "SET R0 2\nloop:\nSUB R0 1\nCMP R0 0\nIF GREATER\nJUMP loop\nEND\nMOVE"
+            .parse()
+            .unwrap();
+        // Not enough cycles for the IF
+        assert_eq!(run(&mut program, &Empty, 4), None);
+        assert_eq!(program.get(Register::INT(0)), 1.0);
+        assert_eq!(program.program_counter, 3);
+        // The rest of the loop, then the MOVE
+        assert_eq!(run(&mut program, &Empty, 20), Some(SwarmCommand::MOVE));
+        assert_eq!(program.get(Register::INT(0)), 0.0);
+        assert_eq!(program.program_counter, 0);
+    }
+
+    #[test]
+    fn run_costs() {
+        let mut program: SwarmProgram = "SET R0 1\nFORMATION GATHER\nFIRE".parse().unwrap();
+        // The FORMATION doesn't fit after the SET
+        assert_eq!(run(&mut program, &Empty, 8), None);
+        // But it still runs when the budget is smaller than its cost
+        assert_eq!(
+            run(&mut program, &Empty, 4),
+            Some(SwarmCommand::FORMATION(::swarm_language::Formation::GATHER, None))
+        );
+        // Actions end the tick even with cycles left over
+        assert_eq!(run(&mut program, &Empty, 100), Some(SwarmCommand::FIRE));
+        assert_eq!(program.program_counter, 0);
+    }
+
+    #[test]
+    fn run_idle() {
+        // Programs with only handlers wait for their events
+        let mut program: SwarmProgram = "ON HIT\nFIRE\nEND".parse().unwrap();
+        assert_eq!(run(&mut program, &Empty, 20), None);
+        assert_eq!((program.program_counter, program.fault), (0, None));
+        assert!(program.interrupt(::swarm_language::Event::HIT));
+        assert_eq!(run(&mut program, &Empty, 20), Some(SwarmCommand::FIRE));
+        // Then go back to waiting once the handler returns
+        assert_eq!(run(&mut program, &Empty, 20), None);
+        assert_eq!(program.fault, None);
+        assert!(program.is_idle());
+
+        // Procedures are never called without a main body
+        let mut program: SwarmProgram = "DEF step\nMOVE\nEND".parse().unwrap();
+        assert_eq!(run(&mut program, &Empty, 20), None);
+        assert_eq!((program.current_line(), program.fault), (None, None));
+    }
+}
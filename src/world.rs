@@ -14,13 +14,24 @@
 // You should have received a copy of the GNU General Public License
 // along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
 extern crate serde_json;
-use entity::{Bullet, Swarm};
-use swarm_language::SwarmProgram;
-use std::collections::HashMap;
-use rand::{thread_rng, Rng};
+use config::Rules;
+use entity::{Bullet, BulletState, Swarm, SwarmMember, SwarmState};
+use error::GenericError;
+use replay::{Replay, ReplayEvent};
+use rpc::Viewport;
+use serde::{Deserialize, Deserializer};
+use spatial::SpatialGrid;
+use swarm_language::{Event, SwarmProgram, Target};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use rand::{thread_rng, Rng, SeedableRng, XorShiftRng};
 use std::time::{Duration, Instant};
 /// Represents the state of the game's world
-#[derive(Clone, Debug)]
+/// Can be saved as a snapshot and restored exactly
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct World {
     /// The width of the world
     pub width: f32,
@@ -28,27 +39,65 @@ pub struct World {
     pub height: f32,
     /// Each swarm in the world
     /// Map of player ID to swarm
-    pub swarms: HashMap<usize, Swarm>,
+    /// Ordered by ID so swarms always update in the same order
+    pub swarms: BTreeMap<usize, Swarm>,
     /// Each bullet in the world
     /// TODO: vec and element swap
     pub bullets: Vec<Bullet>,
+    /// Number of bullets fired, which is also the ID of the newest bullet
+    /// Bullet IDs start at 1, so bullets from before IDs existed can't clash with new ones
+    #[serde(default)]
+    pub bullets_fired: u64,
 
     /// Leaderboard of players, from 1st place to 10th place
     /// Tuple of (ID, experience)
     pub leaderboard: Vec<(usize, i64)>,
+    /// Number of ticks the world has run
+    pub tick: u64,
+    /// The balance of the game
+    pub rules: Rules,
+    /// The seed the world's randomness started from
+    pub seed: u64,
+    /// Source of all randomness in the world
+    /// The same seed and inputs always produce the same world
+    rng: WorldRng,
+    /// Record of everything players have done, if the world is recording one
+    /// Left out of snapshots, since it grows for as long as the world runs
+    #[serde(skip)]
+    pub replay: Option<Replay>,
+}
+/// Writes a file, writing next to it first so a crash can't leave half of one
+pub fn write_file(path: &Path, contents: &str) -> Result<(), GenericError> {
+    let partial = path.with_extension("partial");
+    File::create(&partial)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|_| fs::rename(&partial, path))
+        .map_err(|error| GenericError::new(format!("{}: {}", path.display(), error)))
 }
+
 /// Functions for the world
 impl World {
+    /// Distance outside a client's viewport that entities are still sent
+    /// Keeps swarms and bullets from popping in at the edges of the screen
+    pub const VIEWPORT_MARGIN: f32 = 100.0;
     /// Constructor
     /// width: the width of the world
     /// height: the height of the world
+    /// The world is seeded randomly; use with_seed to reproduce a world
     pub fn new(width: f32, height: f32) -> Self {
+        let seed: u64 = thread_rng().gen();
         World {
             width: width,
             height: height,
-            swarms: HashMap::new(),
+            swarms: BTreeMap::new(),
             bullets: Vec::new(),
+            bullets_fired: 0,
             leaderboard: Vec::new(),
+            tick: 0,
+            rules: Rules::default(),
+            seed: seed,
+            rng: WorldRng::new(seed),
+            replay: None,
         }
     }
     /// Capacity constructor
@@ -57,23 +106,66 @@ impl World {
     /// capacity: the number players to allocate space for
     /// Space is allocated for 100x the number of bullets
     pub fn with_capacity(width: f32, height: f32, capacity: usize) -> Self {
-        World {
-            width: width,
-            height: height,
-            swarms: HashMap::with_capacity(capacity),
-            bullets: Vec::with_capacity(capacity * 10),
-            leaderboard: Vec::new(),
+        let mut world = World::new(width, height);
+        world.bullets.reserve(capacity * 10);
+        world
+    }
+    /// Supplementary function to seed the world's randomness
+    /// Should be called before any players are added
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.rng = WorldRng::new(seed);
+        if let Some(ref mut replay) = self.replay {
+            replay.seed = seed;
         }
+        self
+    }
+    /// Supplementary function to change the balance of the game
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        if let Some(ref mut replay) = self.replay {
+            replay.rules = rules;
+        }
+        self
+    }
+    /// Supplementary function to record a replay of everything players do
+    /// Should be called before any players are added
+    pub fn with_replay(mut self) -> Self {
+        let replay = Replay::new(self.width, self.height, self.seed);
+        self.replay = Some(replay.with_rules(self.rules));
+        self
+    }
+    /// Adds an event to the replay, if one is being recorded
+    fn record(&mut self, event: ReplayEvent) {
+        if let Some(ref mut replay) = self.replay {
+            replay.events.push((self.tick, event));
+        }
+    }
+    /// Reads a snapshot of a world from a file
+    pub fn load(path: &Path) -> Result<Self, GenericError> {
+        File::open(path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| serde_json::from_reader(file).map_err(|error| error.to_string()))
+            .map_err(|error| GenericError::new(format!("{}: {}", path.display(), error)))
+    }
+    /// Serializes a snapshot of the world, to be written once the world is no longer needed
+    pub fn snapshot(&self) -> Result<String, GenericError> {
+        serde_json::to_string(self)
+            .map_err(|error| GenericError::new(format!("Failed to serialize the world: {}", error)))
+    }
+    /// Writes a snapshot of the world to a file
+    pub fn save(&self, path: &Path) -> Result<(), GenericError> {
+        write_file(path, &self.snapshot()?)
     }
     /// Adds a player to the server with the given ID
     pub fn add_player(&mut self, id: usize) {
         info!("Adding player {} to the server", id);
-        // TODO: determine the initial number of members to make
-        let initial_num_members: usize = 10;
+        self.record(ReplayEvent::JOIN(id));
+        let initial_num_members: usize = self.rules.initial_swarm_size;
         // Get a random position
         let (x, y) = self.random_position();
         // Get a random color
-        let color = World::random_color();
+        let color = self.random_color();
         self.swarms
             .insert(id, Swarm::new(x, y, initial_num_members).with_color(color));
     }
@@ -81,6 +173,7 @@ impl World {
     /// Removes a player to the server with the given ID
     pub fn remove_player(&mut self, id: usize) {
         info!("Removing player {} from the server", id);
+        self.record(ReplayEvent::LEAVE(id));
         // Remove the player's data
         match self.swarms.remove(&id) {
             _ => {}
@@ -111,31 +204,37 @@ impl World {
     }
 
     /// Updates a player's program
-    pub fn update_program(&mut self, player_id: usize, program: SwarmProgram) {
+    /// The new program starts from the beginning with cleared registers
+    pub fn update_program(&mut self, player_id: usize, mut program: SwarmProgram) {
+        if self.replay.is_some() {
+            // The original source keeps the line numbers clients are shown
+            let source = if program.source.is_empty() {
+                program.to_string()
+            } else {
+                program.source.clone()
+            };
+            self.record(ReplayEvent::PROGRAM(player_id, source));
+        }
+        program.reset();
         match self.swarms.get_mut(&player_id) {
             Some(swarm) => swarm.program = program,
             None => warn!("Invalid player id: {}", player_id),
         }
     }
     /// Generates a random position
-    fn random_position(&self) -> (f32, f32) {
-        // Get the rng
-        let mut rng = thread_rng();
+    fn random_position(&mut self) -> (f32, f32) {
         // Defines a margin
         // TODO: make this an associated const
         let margin: f32 = 50.0;
         // Generate the position
-        (
-            rng.gen_range(margin, self.width - margin),
-            rng.gen_range(margin, self.height - margin),
-        )
+        let x: f32 = self.rng.gen_range(margin, self.width - margin);
+        let y: f32 = self.rng.gen_range(margin, self.height - margin);
+        (x, y)
     }
     /// Generates a random color
-    fn random_color() -> (u8, u8, u8) {
-        // Get the RNG
-        let mut rng = thread_rng();
+    fn random_color(&mut self) -> (u8, u8, u8) {
         // Generate the color
-        (rng.gen(), rng.gen(), rng.gen())
+        (self.rng.gen(), self.rng.gen(), self.rng.gen())
     }
     /// Performs one "tick" of the world
     /// return: The amount of time elapsed during the tick
@@ -145,20 +244,48 @@ impl World {
     pub fn update(&mut self) -> Duration {
         // Record time at beginning of update
         let start_time = Instant::now();
-        let mut exp_queue: Vec<(usize, i64)> = Vec::new();
 
         // start by updating leaderboard
         self.update_leaderboard();
 
+        // Swarms sense the world as it was at the start of the tick
+        let view = self.view();
+
         // Update each member of the swarm with its own program
+        let old_num_bullets: usize = self.bullets.len();
         for (id, swarm) in self.swarms.iter_mut() {
-            swarm.update(*id, self.width, self.height, &mut self.bullets);
+            swarm.update(*id, &view, &mut self.bullets);
+        }
+        // Give the bullets just fired their IDs
+        for bullet in self.bullets[old_num_bullets..].iter_mut() {
+            self.bullets_fired += 1;
+            bullet.id = self.bullets_fired;
+        }
+
+        // Index the area each swarm can be hit in, so bullets only check swarms near them
+        // Swarms are added in ID order, so they are checked in the same order as before
+        let swarm_member_radius: f32 = 10.0;
+        let mut grid = SpatialGrid::new(self.width, self.height, SpatialGrid::CELL_SIZE);
+        for (id, swarm) in self.swarms.iter() {
+            // Formations can spread members far from the center
+            let epsilon: f32 = swarm.extent() + swarm_member_radius;
+            grid.insert(
+                *id,
+                swarm.x - epsilon,
+                swarm.y - epsilon,
+                swarm.x + epsilon,
+                swarm.y + epsilon,
+            );
         }
 
         // Update each bullet
         let mut i: usize = 0;
         let mut upper_bound_bullets: usize = self.bullets.len();
         let mut exp_queue: Vec<(usize, i64)> = Vec::new();
+        // Events for swarms other than the one being checked
+        let mut event_queue: Vec<(usize, Event)> = Vec::new();
+        // Owners of bullets that hit, to credit them with the damage
+        let mut damage_queue: Vec<usize> = Vec::new();
         'outer: while i < upper_bound_bullets {
             // position update bullets
 
@@ -173,74 +300,328 @@ impl World {
 
             // collision detection here
 
-            // check each swarm
-            for (id, swarm) in self.swarms.iter_mut() {
-                // TODO: choose the epsilon to consider as "incoming dangerous
-                // bullets"
-                let epsilon: f32 = 60.0;
+            // check each swarm near the bullet
+            for &id in grid.query(self.bullets[i].x, self.bullets[i].y) {
+                let swarm = match self.swarms.get_mut(&id) {
+                    Some(swarm) => swarm,
+                    None => continue,
+                };
+                // collision detection
+                // Formations can spread members far from the center
+                let epsilon: f32 = swarm.extent() + swarm_member_radius;
                 if (self.bullets[i].x - swarm.x).abs() <= epsilon
                     && (self.bullets[i].y - swarm.y).abs() <= epsilon
+                    && self.bullets[i].owner != id
                 {
-                    let mut j: usize = 0;
-                    let mut upper_bound_members = swarm.members.len();
-                    while j < upper_bound_members {
-                        // collision detection
-                        let swarm_member_radius: f32 = 10.0;
-                        // detect colllision
-                        // for now detects if the bullet passes within a
-                        // square hitbox around the swarm member
-                        if (self.bullets[i].x - (swarm.x + swarm.members[j].x)).abs()
-                            <= swarm_member_radius
-                            && (self.bullets[i].y - (swarm.y + swarm.members[j].y)).abs()
-                                <= swarm_member_radius
-                            && self.bullets[i].owner != *id
-                        {
-                            swarm.members[j].health -= 1;
-                            exp_queue.push((self.bullets[i].owner, 10));
-                            debug!("HIT");
-                            if swarm.members[j].health == 0 {
-                                exp_queue.push((self.bullets[i].owner, 50));
-
-                                debug!("KILL");
-                                swarm.members.swap_remove(j);
-                                upper_bound_members -= 1;
-                            }
-                            // delete bullet
-                            self.bullets.swap_remove(i);
-                            upper_bound_bullets -= 1;
-                            continue 'outer;
+                    // detect colllision
+                    // for now detects if the bullet passes within a
+                    // square hitbox around the swarm member
+                    // The first member in the bullet's path takes the hit, covering the others
+                    let hit = {
+                        let bullet = &self.bullets[i];
+                        // How far along the bullet's path a member is
+                        let (sin, cos) = bullet.direction.to_radians().sin_cos();
+                        let along = |member: &SwarmMember| {
+                            (swarm.x + member.x - bullet.x) * cos
+                                - (swarm.y + member.y - bullet.y) * sin
+                        };
+                        swarm
+                            .members
+                            .iter()
+                            .enumerate()
+                            .filter(|&(_, member)| {
+                                (bullet.x - (swarm.x + member.x)).abs() <= swarm_member_radius
+                                    && (bullet.y - (swarm.y + member.y)).abs()
+                                        <= swarm_member_radius
+                            })
+                            .min_by(|&(_, a), &(_, b)| {
+                                along(a).partial_cmp(&along(b)).unwrap_or(Ordering::Equal)
+                            })
+                            .map(|(j, _)| j)
+                    };
+                    if let Some(j) = hit {
+                        swarm.members[j].health -= 1;
+                        swarm.stats.damage_taken += 1;
+                        damage_queue.push(self.bullets[i].owner);
+                        exp_queue.push((self.bullets[i].owner, 10));
+                        debug!("HIT");
+                        // Only one handler can start, so losing a member takes priority over the hit
+                        let mut handled: bool = false;
+                        if swarm.members[j].health == 0 {
+                            exp_queue.push((self.bullets[i].owner, 50));
+
+                            debug!("KILL");
+                            swarm.members.swap_remove(j);
+                            handled = swarm.program.interrupt(Event::MEMBER_LOST);
+                            event_queue.push((self.bullets[i].owner, Event::KILL));
                         }
-                        j += 1;
+                        if !handled {
+                            swarm.program.interrupt(Event::HIT);
+                        }
+                        // delete bullet
+                        self.bullets.swap_remove(i);
+                        upper_bound_bullets -= 1;
+                        continue 'outer;
                     }
                 }
             }
-            // update appropriate experience
-            for &(id, exp) in exp_queue.iter() {
-                if let Some(mut e_swarm) = self.swarms.get_mut(&id) {
-                    e_swarm.experience += exp;
-                }
-            }
-            exp_queue.clear();
             // increment to next bullet
             i += 1;
         }
+        // update appropriate experience
+        // Done after every bullet so hits from the last bullet count too
+        for &(id, exp) in exp_queue.iter() {
+            if let Some(mut e_swarm) = self.swarms.get_mut(&id) {
+                e_swarm.experience += exp;
+            }
+        }
+        // Let shooters know about their kills
+        for (id, event) in event_queue {
+            if let Some(swarm) = self.swarms.get_mut(&id) {
+                if event == Event::KILL {
+                    swarm.stats.kills += 1;
+                }
+                swarm.program.interrupt(event);
+            }
+        }
+        for id in damage_queue {
+            if let Some(swarm) = self.swarms.get_mut(&id) {
+                swarm.stats.damage_dealt += 1;
+            }
+        }
+        // Record when swarms lose their last member
+        self.tick += 1;
+        for swarm in self.swarms.values_mut() {
+            if swarm.members.is_empty() && swarm.stats.eliminated_at.is_none() {
+                swarm.stats.eliminated_at = Some(self.tick);
+            }
+        }
         // Record time at end of update and return the time elapsed
         Instant::now().duration_since(start_time)
     }
+    /// Builds a read-only view of the world for swarms to sense
+    pub fn view(&self) -> WorldView {
+        WorldView {
+            width: self.width,
+            height: self.height,
+            rules: self.rules,
+            swarms: self.swarms
+                .iter()
+                .map(|(id, swarm)| SwarmSummary {
+                    id: *id,
+                    x: swarm.x,
+                    y: swarm.y,
+                    num_members: swarm.members.len(),
+                    health: swarm.members.iter().map(|member| member.health).sum(),
+                    experience: swarm.experience,
+                })
+                .collect(),
+            bullets: self.bullets
+                .iter()
+                .map(|bullet| (bullet.owner, bullet.x, bullet.y))
+                .collect(),
+        }
+    }
     /// Returns the world in byte representation
     /// Used to render the world on a client
     pub fn get_state(&self) -> WorldState {
         WorldState {
-            swarms: self.swarms.clone(),
-            bullets: self.bullets.clone(),
+            tick: self.tick,
+            swarms: self.swarms
+                .iter()
+                .map(|(id, swarm)| (*id, swarm.state()))
+                .collect(),
+            bullets: self.bullets.iter().map(|bullet| bullet.state()).collect(),
+        }
+    }
+    /// Returns the part of the world near a client's viewport
+    /// The player's own swarm is always included
+    pub fn get_visible_state(&self, player_id: usize, viewport: &Viewport) -> WorldState {
+        WorldState {
+            tick: self.tick,
+            swarms: self.swarms
+                .iter()
+                .filter(|&(id, swarm)| {
+                    // Formations can spread members far from the center
+                    let extent = swarm.extent();
+                    *id == player_id || viewport.overlaps(
+                        swarm.x - extent,
+                        swarm.y - extent,
+                        swarm.x + extent,
+                        swarm.y + extent,
+                        World::VIEWPORT_MARGIN,
+                    )
+                })
+                .map(|(id, swarm)| (*id, swarm.state()))
+                .collect(),
+            bullets: self.bullets
+                .iter()
+                .filter(|bullet| {
+                    viewport.overlaps(bullet.x, bullet.y, bullet.x, bullet.y, World::VIEWPORT_MARGIN)
+                })
+                .map(|bullet| bullet.state())
+                .collect(),
         }
     }
 }
 
+/// The world's random number generator
+/// Saved as its seed and the number of values drawn, since the generator's own state is private
 #[derive(Clone, Debug, Serialize)]
+struct WorldRng {
+    /// The seed the generator started from
+    seed: u64,
+    /// Number of values drawn since the generator was seeded
+    draws: u64,
+    /// The generator
+    #[serde(skip_serializing)]
+    rng: XorShiftRng,
+}
+
+/// Functions for the world's random number generator
+impl WorldRng {
+    /// Constructor
+    fn new(seed: u64) -> Self {
+        let low: u32 = seed as u32;
+        let high: u32 = (seed >> 32) as u32;
+        WorldRng {
+            seed: seed,
+            draws: 0,
+            // XorShift can't be seeded with all zeroes, so mix in constants
+            rng: XorShiftRng::from_seed([low, high, low ^ 0x9E37_79B9, high ^ 0x7F4A_7C15]),
+        }
+    }
+}
+
+/// Allows the world's generator to be used for randomness
+impl Rng for WorldRng {
+    /// Draws a value, counting it
+    /// Every other kind of value is built from these
+    fn next_u32(&mut self) -> u32 {
+        self.draws += 1;
+        self.rng.next_u32()
+    }
+}
+
+/// Allows the world's generator to be restored from a snapshot
+impl<'de> Deserialize<'de> for WorldRng {
+    /// Reseeds the generator and draws values until it is where it was saved
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        /// How the generator is saved
+        #[derive(Deserialize)]
+        struct Saved {
+            seed: u64,
+            draws: u64,
+        }
+        let saved = Saved::deserialize(deserializer)?;
+        let mut rng = WorldRng::new(saved.seed);
+        for _ in 0..saved.draws {
+            rng.next_u32();
+        }
+        Ok(rng)
+    }
+}
+
+/// A read-only view of the world given to swarms each tick
+/// Used to evaluate the sensors in swarm programs
+#[derive(Clone, Debug)]
+pub struct WorldView {
+    /// The width of the world
+    pub width: f32,
+    /// The height of the world
+    pub height: f32,
+    /// The balance of the game
+    pub rules: Rules,
+    /// Summary of each swarm in the world
+    pub swarms: Vec<SwarmSummary>,
+    /// Each bullet in the world as (owner, x, y)
+    pub bullets: Vec<(usize, f32, f32)>,
+}
+/// Functions for the world view
+impl WorldView {
+    /// Distance from a point to the nearest living swarm not owned by the given player
+    pub fn nearest_enemy_distance(&self, id: usize, x: f32, y: f32) -> Option<f32> {
+        self.swarms
+            .iter()
+            .filter(|swarm| swarm.id != id && swarm.num_members > 0)
+            .map(|swarm| (swarm.x - x).hypot(swarm.y - y))
+            .fold(None, |nearest, distance| match nearest {
+                Some(nearest) if nearest <= distance => Some(nearest),
+                _ => Some(distance),
+            })
+    }
+    /// Finds the living swarm not owned by the given player that best matches a target
+    /// Ties are broken by distance from the given point
+    pub fn find_target(&self, id: usize, x: f32, y: f32, target: Target) -> Option<&SwarmSummary> {
+        let distance = |swarm: &SwarmSummary| (swarm.x - x).hypot(swarm.y - y);
+        self.swarms
+            .iter()
+            .filter(|swarm| swarm.id != id && swarm.num_members > 0)
+            .fold(None, |best: Option<&SwarmSummary>, swarm| {
+                let better = match best {
+                    None => true,
+                    Some(best) => {
+                        // Primary criterion for the target, with distance as a tiebreaker
+                        let order = match target {
+                            Target::NEAREST => Ordering::Equal,
+                            Target::WEAKEST => swarm.health.cmp(&best.health),
+                            Target::LEADER => best.experience.cmp(&swarm.experience),
+                        };
+                        order == Ordering::Less
+                            || (order == Ordering::Equal && distance(swarm) < distance(best))
+                    }
+                };
+                if better {
+                    Some(swarm)
+                } else {
+                    best
+                }
+            })
+    }
+    /// Number of bullets not owned by the given player within a distance of a point
+    pub fn bullets_within(&self, id: usize, x: f32, y: f32, distance: f32) -> usize {
+        self.bullets
+            .iter()
+            .filter(|&&(owner, bullet_x, bullet_y)| {
+                owner != id && (bullet_x - x).hypot(bullet_y - y) <= distance
+            })
+            .count()
+    }
+    /// Distance from a point to the closest edge of the world
+    pub fn wall_distance(&self, x: f32, y: f32) -> f32 {
+        x.min(self.width - x).min(y).min(self.height - y)
+    }
+}
+
+/// What a swarm can see of another swarm
+#[derive(Clone, Copy, Debug)]
+pub struct SwarmSummary {
+    /// ID of the player who owns the swarm
+    pub id: usize,
+    /// X position
+    pub x: f32,
+    /// Y position
+    pub y: f32,
+    /// Number of members left in the swarm
+    pub num_members: usize,
+    /// Total health of the members
+    pub health: i32,
+    /// Experience gained by the swarm
+    pub experience: i64,
+}
+
+/// What clients are sent about the world
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct WorldState {
-    swarms: HashMap<usize, Swarm>,
-    bullets: Vec<Bullet>,
+    /// The tick the state is from, which clients acknowledge it by
+    pub tick: u64,
+    /// Each swarm, by player ID
+    pub swarms: BTreeMap<usize, SwarmState>,
+    /// Each bullet
+    pub bullets: Vec<BulletState>,
 }
 
 #[cfg(test)]
@@ -259,4 +640,248 @@ mod tests {
         }
         assert!(world.leaderboard.len() <= 10);
     }
+    #[test]
+    fn test_update_program() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.add_player(0);
+        let mut program: SwarmProgram = "SET R0 5\nMOVE".parse().unwrap();
+        program.int_registers[0] = 5;
+        program.program_counter = 1;
+        world.update_program(0, program);
+        let program = &world.swarms[&0].program;
+        assert_eq!(program.program_counter, 0);
+        assert_eq!(program.int_registers[0], 0);
+    }
+    #[test]
+    fn test_view() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.swarms.insert(0, Swarm::new(100.0, 100.0, 1));
+        world.swarms.insert(1, Swarm::new(130.0, 140.0, 1));
+        world.bullets.push(Bullet::new(1, 110.0, 100.0, 0.0));
+        world.bullets.push(Bullet::new(0, 100.0, 110.0, 0.0));
+        let view = world.view();
+        assert_eq!(view.nearest_enemy_distance(0, 100.0, 100.0), Some(50.0));
+        assert_eq!(view.bullets_within(0, 100.0, 100.0, 20.0), 1);
+        assert_eq!(view.wall_distance(100.0, 950.0), 50.0);
+    }
+    #[test]
+    fn test_event_handlers() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.swarms.insert(0, Swarm::new(500.0, 500.0, 1));
+        world.swarms.insert(1, Swarm::new(100.0, 100.0, 1));
+        world.update_program(0, "NOOP\nON HIT\nTURN 10\nEND".parse().unwrap());
+        world.update_program(1, "NOOP\nON KILL\nTURN -10\nEND".parse().unwrap());
+        world.swarms.get_mut(&0).unwrap().members[0].health = 1;
+        // A bullet that reaches the member this tick
+        let (x, y) = {
+            let swarm = &world.swarms[&0];
+            (swarm.x + swarm.members[0].x, swarm.y + swarm.members[0].y)
+        };
+        world.bullets.push(Bullet::new(1, x - 5.0, y, 0.0));
+        world.update();
+        assert_eq!(world.swarms[&0].program.handler_depth, Some(0));
+        assert_eq!(world.swarms[&1].program.handler_depth, Some(0));
+        // The handlers run, then both programs resume
+        world.update();
+        assert_eq!(world.swarms[&0].direction, 10.0);
+        assert_eq!(world.swarms[&1].direction, -10.0);
+        world.update();
+        assert_eq!(world.swarms[&0].program.handler_depth, None);
+        assert_eq!(world.swarms[&0].program.program_counter, 0);
+    }
+    #[test]
+    fn test_member_lost_handler() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.swarms.insert(0, Swarm::new(500.0, 500.0, 2));
+        world.swarms.insert(1, Swarm::new(100.0, 100.0, 1));
+        world.update_program(0, "NOOP\nON HIT\nTURN 10\nEND\nON MEMBER_LOST\nTURN -20\nEND".parse().unwrap());
+        world.update_program(1, "NOOP".parse().unwrap());
+        world.swarms.get_mut(&0).unwrap().members[0].health = 1;
+        let (x, y) = {
+            let swarm = &world.swarms[&0];
+            (swarm.x + swarm.members[0].x, swarm.y + swarm.members[0].y)
+        };
+        world.bullets.push(Bullet::new(1, x - 5.0, y, 0.0));
+        world.update();
+        assert_eq!(world.swarms[&0].members.len(), 1);
+        // A killing hit runs the MEMBER_LOST handler instead of the HIT one
+        world.update();
+        assert_eq!(world.swarms[&0].direction, -20.0);
+    }
+    #[test]
+    fn test_formation_cover() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.swarms.insert(0, Swarm::new(500.0, 500.0, 3));
+        world.swarms.insert(1, Swarm::new(100.0, 100.0, 1));
+        world.update_program(0, "FORMATION COLUMN 8".parse().unwrap());
+        world.update_program(1, "NOOP".parse().unwrap());
+        // Wait for the members to get into formation
+        for _ in 0..15 {
+            world.update();
+        }
+        // A bullet from behind overlapping the back two members hits the one at the back
+        world.bullets.push(Bullet::new(1, 500.0 - 10.0, 500.0, 0.0));
+        world.update();
+        let health: Vec<i32> = world.swarms[&0].members.iter().map(|member| member.health).collect();
+        assert_eq!(health, vec![5, 5, 4]);
+        assert!(world.bullets.is_empty());
+    }
+    #[test]
+    fn test_transition_collision() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.swarms.insert(0, Swarm::new(500.0, 500.0, 2));
+        world.swarms.insert(1, Swarm::new(100.0, 100.0, 1));
+        world.update_program(0, "FORMATION LINE 90".parse().unwrap());
+        world.update_program(1, "NOOP".parse().unwrap());
+        for _ in 0..5 {
+            world.update();
+        }
+        // Partway to its slot, the member can be hit where it is now
+        let (x, y) = {
+            let member = &world.swarms[&0].members[1];
+            assert!(member.y != member.target_y);
+            (500.0 + member.x, 500.0 + member.y)
+        };
+        world.bullets.push(Bullet::new(1, x - 5.0, y, 0.0));
+        world.update();
+        assert_eq!(world.swarms[&0].members[1].health, 4);
+    }
+
+    #[test]
+    fn test_find_target() {
+        let mut world = World::new(1000.0, 1000.0);
+        world.swarms.insert(0, Swarm::new(100.0, 100.0, 5));
+        world.swarms.insert(1, Swarm::new(200.0, 100.0, 5));
+        world.swarms.insert(2, Swarm::new(500.0, 100.0, 2));
+        world.swarms.insert(3, Swarm::new(900.0, 100.0, 5));
+        world.swarms.get_mut(&3).unwrap().experience = 100;
+        let view = world.view();
+        let target = |target| view.find_target(0, 100.0, 100.0, target).map(|swarm| swarm.id);
+        assert_eq!(target(Target::NEAREST), Some(1));
+        assert_eq!(target(Target::WEAKEST), Some(2));
+        assert_eq!(target(Target::LEADER), Some(3));
+    }
+
+    #[test]
+    fn test_determinism() {
+        // Runs a world with fighting swarms and returns its final state
+        let run = |seed: u64| {
+            let mut world = World::new(1000.0, 1000.0).with_seed(seed);
+            for id in 0..6 {
+                world.add_player(id);
+                world.update_program(id, "AIM NEAREST\nMOVE\nFIRE\nTURN 7".parse().unwrap());
+            }
+            for _ in 0..300 {
+                world.update();
+            }
+            serde_json::to_string(&world).unwrap()
+        };
+        assert_eq!(run(42), run(42));
+        assert!(run(42) != run(43));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut world = World::new(800.0, 600.0).with_seed(3);
+        for id in 0..4 {
+            world.add_player(id);
+            world.update_program(id, "loop:\nAIM NEAREST\nCALL step\nJUMP loop\nDEF step\nMOVE\nFIRE\nEND".parse().unwrap());
+        }
+        for _ in 0..75 {
+            world.update();
+        }
+        let json = serde_json::to_string(&world).unwrap();
+        let mut restored: World = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+        // The restored world carries on exactly like the original
+        world.add_player(4);
+        restored.add_player(4);
+        for _ in 0..75 {
+            world.update();
+            restored.update();
+        }
+        assert_eq!(serde_json::to_string(&restored).unwrap(), serde_json::to_string(&world).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_overflow() {
+        let mut world = World::new(800.0, 600.0).with_seed(3);
+        world.add_player(0);
+        world.update_program(0, "SET F0 1e38\nMUL F0 1e38\nSET F1 F0\nMUL F1 -1".parse().unwrap());
+        for _ in 0..10 {
+            world.update();
+        }
+        // Overflowing registers are clamped, so the snapshot can be read back
+        let registers = world.swarms[&0].program.float_registers;
+        assert!(registers.iter().all(|register| register.is_finite()));
+        assert_eq!(registers[1], ::std::f32::MIN);
+        let json = serde_json::to_string(&world).unwrap();
+        let restored: World = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), json);
+    }
+
+    #[test]
+    fn test_snapshot_without_replay() {
+        let mut world = World::new(800.0, 600.0).with_seed(3).with_replay();
+        world.add_player(0);
+        world.update();
+        // The replay is saved on its own, so snapshots stay the same size
+        let json = serde_json::to_string(&world).unwrap();
+        assert!(!json.contains("events"));
+        let restored: World = serde_json::from_str(&json).unwrap();
+        assert!(restored.replay.is_none());
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut rules = Rules::default();
+        rules.initial_swarm_size = 4;
+        rules.bullet_lifetime = 2;
+        let mut world = World::new(1000.0, 1000.0).with_rules(rules);
+        world.add_player(0);
+        world.update_program(0, "FIRE".parse().unwrap());
+        assert_eq!(world.swarms[&0].members.len(), 4);
+        world.update();
+        assert_eq!(world.bullets.len(), 4);
+        // Bullets run out after the configured lifetime
+        world.update();
+        assert!(world.bullets.is_empty());
+        // Programs get more done each tick with a larger cycle budget
+        let counted = |cycle_budget: usize| {
+            let mut rules = Rules::default();
+            rules.cycle_budget = cycle_budget;
+            let mut world = World::new(1000.0, 1000.0).with_rules(rules);
+            world.add_player(0);
+            world.update_program(0, "ADD R0 1".parse().unwrap());
+            world.update();
+            world.swarms[&0].program.int_registers[0]
+        };
+        assert!(counted(Swarm::CYCLE_BUDGET) > 0);
+        assert_eq!(counted(2 * Swarm::CYCLE_BUDGET), 2 * counted(Swarm::CYCLE_BUDGET));
+    }
+    #[test]
+    fn test_visible_state() {
+        let mut world = World::new(2000.0, 2000.0).with_seed(5);
+        for id in 0..3 {
+            world.add_player(id);
+        }
+        world.swarms.get_mut(&0).unwrap().x = 100.0;
+        world.swarms.get_mut(&1).unwrap().x = 1000.0;
+        world.swarms.get_mut(&2).unwrap().x = 1900.0;
+        for swarm in world.swarms.values_mut() {
+            swarm.y = 100.0;
+        }
+        world.bullets.push(Bullet::new(2, 1050.0, 150.0, 0.0));
+        world.bullets.push(Bullet::new(2, 1800.0, 150.0, 0.0));
+        let viewport = Viewport {
+            min_x: 800.0,
+            min_y: 0.0,
+            max_x: 1200.0,
+            max_y: 400.0,
+        };
+        let state = world.get_visible_state(2, &viewport);
+        // Swarm 0 is out of view, but the player's own swarm is always sent
+        assert_eq!(state.swarms.keys().cloned().collect::<Vec<usize>>(), vec![1, 2]);
+        assert_eq!(state.bullets.len(), 1);
+    }
 }
@@ -0,0 +1,62 @@
+// Copyright 2018 Steven Sheffey
+// This file is part of heroesoftheswarm.
+//
+// heroesoftheswarm is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// heroesoftheswarm is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with heroesoftheswarm.  If not, see <http://www.gnu.org/licenses/>.
+extern crate heroesoftheswarm;
+extern crate serde_json;
+extern crate websocket;
+
+use heroesoftheswarm::config::ServerConfig;
+use heroesoftheswarm::server::{GameServer, ServerHandle};
+use serde_json::Value;
+use websocket::OwnedMessage;
+use websocket::sync::client::ClientBuilder;
+
+/// Starts a server on a port the system picks
+fn start() -> ServerHandle {
+    let mut config = ServerConfig::default();
+    config.hostname = "127.0.0.1".into();
+    config.port = 0;
+    GameServer::new(config).start().unwrap()
+}
+
+/// Connects to a server as a player, returning the ID the server gives it
+fn join(server: &ServerHandle) -> u64 {
+    let url = format!("ws://{}", server.local_addr());
+    let mut client = ClientBuilder::new(&url)
+        .unwrap()
+        .add_protocol("heroesoftheswarm")
+        .connect_insecure()
+        .unwrap();
+    match client.recv_message().unwrap() {
+        OwnedMessage::Text(text) => {
+            let config: Value = serde_json::from_str(&text).unwrap();
+            config["message"]["config"]["player_id"].as_u64().unwrap()
+        }
+        message => panic!("Expected the config, got {:?}", message),
+    }
+}
+
+#[test]
+fn test_two_servers() {
+    let first = start();
+    let second = start();
+    assert!(first.local_addr() != second.local_addr());
+    // Each server has its own world, so each gives out IDs from 0
+    assert_eq!(join(&first), 0);
+    assert_eq!(join(&second), 0);
+    assert_eq!(join(&first), 1);
+    first.shutdown().unwrap();
+    second.shutdown().unwrap();
+}